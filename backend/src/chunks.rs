@@ -0,0 +1,149 @@
+//! Chunked canvas representation.
+//!
+//! The grid is partitioned into fixed tiles so a new client can be streamed
+//! the canvas chunk-by-chunk (rendering progressively instead of waiting for
+//! one giant payload) and so paints landing in the same chunk in quick
+//! succession coalesce into a single `UPDATE_CHUNK` broadcast instead of one
+//! message per pixel.
+
+use crate::{Pixel, ServerMessage, SharedState, GRID_HEIGHT, GRID_WIDTH};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Chunk tile dimensions. 120x180 grid / (30x36) tiles = 4x5 = 20 chunks.
+pub(crate) const CHUNK_WIDTH: usize = 30;
+pub(crate) const CHUNK_HEIGHT: usize = 36;
+pub(crate) const CHUNK_COLS: usize = GRID_WIDTH / CHUNK_WIDTH;
+pub(crate) const CHUNK_ROWS: usize = GRID_HEIGHT / CHUNK_HEIGHT;
+pub(crate) const CHUNK_COUNT: usize = CHUNK_COLS * CHUNK_ROWS;
+
+/// How often pending per-chunk edits are coalesced into `UPDATE_CHUNK` broadcasts.
+const COALESCE_INTERVAL_MS: u64 = 150;
+
+/// A single pixel changed within an `UPDATE_CHUNK` broadcast.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PixelEdit {
+    pub(crate) index: usize,
+    pub(crate) color: String,
+}
+
+/// Which chunk a grid index falls in.
+pub(crate) fn chunk_id_for_index(index: usize) -> usize {
+    let x = index % GRID_WIDTH;
+    let y = index / GRID_WIDTH;
+    (y / CHUNK_HEIGHT) * CHUNK_COLS + (x / CHUNK_WIDTH)
+}
+
+/// All grid indices belonging to a chunk, in row-major order within it.
+fn indices_in_chunk(chunk_id: usize) -> Vec<usize> {
+    let chunk_row = chunk_id / CHUNK_COLS;
+    let chunk_col = chunk_id % CHUNK_COLS;
+    let y0 = chunk_row * CHUNK_HEIGHT;
+    let x0 = chunk_col * CHUNK_WIDTH;
+
+    let mut indices = Vec::with_capacity(CHUNK_WIDTH * CHUNK_HEIGHT);
+    for y in y0..y0 + CHUNK_HEIGHT {
+        for x in x0..x0 + CHUNK_WIDTH {
+            indices.push(y * GRID_WIDTH + x);
+        }
+    }
+    indices
+}
+
+/// Build the `CHUNK_STATE` message for one chunk, carrying its colors and
+/// its slice of the tree mask so the client can cache the mask per chunk.
+pub(crate) fn chunk_state_message(
+    grid: &[Pixel],
+    tree_mask: &[bool],
+    chunk_id: usize,
+) -> ServerMessage {
+    let indices = indices_in_chunk(chunk_id);
+    let colors = indices.iter().map(|&i| grid[i].color.clone()).collect();
+    let mask = indices.iter().map(|&i| tree_mask[i]).collect();
+    ServerMessage::ChunkState {
+        chunk_id,
+        colors,
+        tree_mask: mask,
+    }
+}
+
+/// Drain whatever per-chunk edits are currently pending and broadcast one
+/// `UPDATE_CHUNK` per dirty chunk.
+async fn drain_and_broadcast(state: &SharedState, tx: &broadcast::Sender<ServerMessage>) {
+    let pending = {
+        let mut state = state.write().await;
+        std::mem::take(&mut state.pending_chunk_edits)
+    };
+
+    for (chunk_id, edits) in pending {
+        if edits.is_empty() {
+            continue;
+        }
+        let edits = edits
+            .into_iter()
+            .map(|(index, color)| PixelEdit { index, color })
+            .collect();
+        let _ = tx.send(ServerMessage::UpdateChunk { chunk_id, edits });
+    }
+}
+
+/// Periodically drain pending per-chunk edits and broadcast one
+/// `UPDATE_CHUNK` per dirty chunk. Exits once `shutdown` fires, so it can be
+/// awaited to completion by `shutdown::WorkerRegistry::drain` - but not
+/// before one last `drain_and_broadcast`, so paints that landed in the final
+/// coalescing window still reach clients still connected to receive
+/// `ServerMessage::ServerShuttingDown` instead of only surviving on disk via
+/// the write-ahead log.
+pub(crate) async fn coalesce_loop(
+    state: SharedState,
+    tx: broadcast::Sender<ServerMessage>,
+    mut shutdown: tokio::sync::watch::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_millis(COALESCE_INTERVAL_MS)) => {
+                drain_and_broadcast(&state, &tx).await;
+            }
+            _ = shutdown.changed() => {
+                tracing::info!("Chunk coalescer task shutting down");
+                break;
+            }
+        }
+    }
+    drain_and_broadcast(&state, &tx).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GRID_SIZE;
+
+    #[test]
+    fn chunk_id_for_index_and_indices_in_chunk_are_inverses() {
+        for chunk_id in 0..CHUNK_COUNT {
+            for index in indices_in_chunk(chunk_id) {
+                assert_eq!(chunk_id_for_index(index), chunk_id);
+            }
+        }
+    }
+
+    #[test]
+    fn every_grid_index_belongs_to_exactly_one_chunk() {
+        let mut seen = vec![false; GRID_SIZE];
+        for chunk_id in 0..CHUNK_COUNT {
+            for index in indices_in_chunk(chunk_id) {
+                assert!(!seen[index], "index {index} covered by more than one chunk");
+                seen[index] = true;
+            }
+        }
+        assert!(seen.iter().all(|&covered| covered), "not every index is covered by a chunk");
+    }
+
+    #[test]
+    fn indices_in_chunk_has_one_entry_per_pixel_in_the_tile() {
+        for chunk_id in 0..CHUNK_COUNT {
+            assert_eq!(indices_in_chunk(chunk_id).len(), CHUNK_WIDTH * CHUNK_HEIGHT);
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! Per-pixel version history, powering time-lapse replay and undo.
+//!
+//! Every accepted paint pushes the pixel's *previous* version onto a bounded
+//! per-pixel stack (see [`PixelHistory`]) before applying the new one. That
+//! stack is what backs `ClientMessage::Undo` - restoring a user's own last
+//! edit without having to re-derive it from the log. `GET /replay` instead
+//! walks the write-ahead log directly (see [`replay_snapshots`]), since undo
+//! only needs a pixel's immediate predecessor but time-lapse needs every
+//! intermediate state in a range.
+
+use crate::persistence::LogEvent;
+use crate::{merge_pixel, Pixel};
+use std::collections::HashMap;
+
+/// Maximum prior versions kept per pixel before the oldest is dropped,
+/// bounding memory regardless of how many times a single pixel gets
+/// repainted.
+const MAX_HISTORY_PER_PIXEL: usize = 20;
+
+/// Bounded per-pixel stacks of prior [`Pixel`] versions, most recent last.
+/// In-memory only - unlike the grid itself, history doesn't survive a
+/// restart, so undo only ever reaches back to edits made since the server
+/// came up.
+#[derive(Default)]
+pub(crate) struct PixelHistory {
+    versions: HashMap<usize, Vec<Pixel>>,
+}
+
+impl PixelHistory {
+    pub(crate) fn new() -> Self {
+        PixelHistory {
+            versions: HashMap::new(),
+        }
+    }
+
+    /// Push `previous` onto `index`'s stack, dropping the oldest entry once
+    /// it would exceed `MAX_HISTORY_PER_PIXEL`.
+    pub(crate) fn push(&mut self, index: usize, previous: Pixel) {
+        let stack = self.versions.entry(index).or_default();
+        stack.push(previous);
+        if stack.len() > MAX_HISTORY_PER_PIXEL {
+            stack.remove(0);
+        }
+    }
+
+    /// Pop and return the most recent prior version for `index`, but only if
+    /// `current` (the pixel's live value) was last modified by `user_id` -
+    /// undo only ever rewinds your own last edit, never someone else's.
+    pub(crate) fn pop_own(&mut self, index: usize, current: &Pixel, user_id: &str) -> Option<Pixel> {
+        if current.modifier_id != user_id {
+            return None;
+        }
+        let stack = self.versions.get_mut(&index)?;
+        let popped = stack.pop();
+        if stack.is_empty() {
+            self.versions.remove(&index);
+        }
+        popped
+    }
+}
+
+/// One reconstructed grid snapshot within a replay range, as streamed by
+/// `GET /replay`.
+#[derive(Debug, Clone, serde::Serialize)]
+pub(crate) struct ReplayFrame {
+    pub(crate) timestamp: u64,
+    pub(crate) colors: Vec<String>,
+}
+
+/// Replay `log` from the beginning, emitting one [`ReplayFrame`] every `step`
+/// edits that land with a timestamp in `from..=to` so a client can animate
+/// the tree filling in without transferring a frame per paint. `log` is
+/// expected to come from `persistence::read_log`, which includes archived
+/// entries truncated out of the live write-ahead log by past snapshot
+/// compactions, so `from` can reach back further than the last compaction.
+///
+/// `log` is *not* assumed chronological: peering (see `peering` module)
+/// appends an inbound `PeerMessage::Paint` to the local WAL whenever the
+/// network delivers it, carrying the *origin* `last_updated`, which can land
+/// anywhere in file order relative to local events. Each event is folded into
+/// the grid through [`merge_pixel`] rather than a raw assignment, so a
+/// later-arriving-but-older-timestamped peer event can never clobber a
+/// genuinely newer one.
+///
+/// Edits before `from` are still applied to reach the right starting grid,
+/// they just don't themselves produce a frame.
+pub(crate) fn replay_snapshots(
+    log: &[LogEvent],
+    grid_size: usize,
+    from: u64,
+    to: u64,
+    step: usize,
+) -> Vec<ReplayFrame> {
+    let step = step.max(1);
+    let mut grid = vec![Pixel::default(); grid_size];
+    let mut frames = Vec::new();
+    let mut applied_in_range = 0usize;
+
+    for event in log {
+        if event.index >= grid_size || event.last_updated > to {
+            continue;
+        }
+
+        let incoming = Pixel {
+            color: event.color.clone(),
+            last_updated: event.last_updated,
+            modifier_id: event.modifier_id.clone(),
+        };
+        grid[event.index] = merge_pixel(&grid[event.index], &incoming);
+
+        if event.last_updated < from {
+            continue;
+        }
+
+        applied_in_range += 1;
+        if applied_in_range.is_multiple_of(step) {
+            frames.push(ReplayFrame {
+                timestamp: event.last_updated,
+                colors: grid.iter().map(|p| p.color.clone()).collect(),
+            });
+        }
+    }
+
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(color: &str, last_updated: u64, modifier_id: &str) -> Pixel {
+        Pixel {
+            color: color.to_string(),
+            last_updated,
+            modifier_id: modifier_id.to_string(),
+        }
+    }
+
+    fn event(index: usize, color: &str, last_updated: u64, modifier_id: &str) -> LogEvent {
+        LogEvent {
+            index,
+            color: color.to_string(),
+            last_updated,
+            modifier_id: modifier_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn replay_snapshots_emits_one_frame_every_step_events() {
+        let log = vec![
+            event(0, "#111111", 1, "alice"),
+            event(0, "#222222", 2, "alice"),
+            event(0, "#333333", 3, "alice"),
+            event(0, "#444444", 4, "alice"),
+        ];
+
+        let frames = replay_snapshots(&log, 1, 1, 4, 2);
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].timestamp, 2);
+        assert_eq!(frames[0].colors, vec!["#222222"]);
+        assert_eq!(frames[1].timestamp, 4);
+        assert_eq!(frames[1].colors, vec!["#444444"]);
+    }
+
+    #[test]
+    fn replay_snapshots_applies_events_before_from_without_framing_them() {
+        let log = vec![
+            event(0, "#111111", 1, "alice"),
+            event(0, "#222222", 5, "alice"),
+        ];
+
+        // Only the second event lands in range, but the grid reflects both.
+        let frames = replay_snapshots(&log, 1, 5, 10, 1);
+
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].colors, vec!["#222222"]);
+    }
+
+    #[test]
+    fn replay_snapshots_excludes_events_after_to() {
+        let log = vec![
+            event(0, "#111111", 1, "alice"),
+            event(0, "#222222", 100, "alice"),
+        ];
+
+        let frames = replay_snapshots(&log, 1, 0, 10, 1);
+
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].colors, vec!["#111111"]);
+    }
+
+    #[test]
+    fn replay_snapshots_treats_a_step_of_zero_as_one() {
+        let log = vec![event(0, "#111111", 1, "alice")];
+        let frames = replay_snapshots(&log, 1, 0, 10, 0);
+        assert_eq!(frames.len(), 1);
+    }
+
+    #[test]
+    fn replay_snapshots_merges_out_of_order_peer_events_instead_of_overwriting() {
+        // A later-arriving peer event (file order) carrying an older origin
+        // timestamp must not clobber a genuinely newer local write.
+        let log = vec![event(0, "#newer", 20, "local"), event(0, "#older", 5, "peer")];
+
+        let frames = replay_snapshots(&log, 1, 0, 20, 1);
+
+        let last = frames.last().expect("at least one frame");
+        assert_eq!(last.colors, vec!["#newer"]);
+    }
+
+    #[test]
+    fn replay_snapshots_skips_out_of_range_indices() {
+        let log = vec![event(5, "#111111", 1, "alice")];
+        let frames = replay_snapshots(&log, 1, 0, 10, 1);
+        assert!(frames.is_empty());
+    }
+
+    #[test]
+    fn pixel_history_push_drops_the_oldest_past_the_cap() {
+        let mut history = PixelHistory::new();
+        for i in 0..MAX_HISTORY_PER_PIXEL + 5 {
+            history.push(0, pixel(&format!("#{i:06}"), i as u64, "alice"));
+        }
+
+        let current = pixel("#current", 1000, "alice");
+        let mut popped = Vec::new();
+        while let Some(p) = history.pop_own(0, &current, "alice") {
+            popped.push(p);
+        }
+
+        assert_eq!(popped.len(), MAX_HISTORY_PER_PIXEL);
+        // The oldest 5 pushes should have been dropped, so the stack bottoms
+        // out at push index 5.
+        assert_eq!(popped.last().unwrap().last_updated, 5);
+    }
+
+    #[test]
+    fn pixel_history_pop_own_refuses_someone_elses_edit() {
+        let mut history = PixelHistory::new();
+        history.push(0, pixel("#111111", 1, "alice"));
+        let current = pixel("#222222", 2, "bob");
+
+        assert_eq!(history.pop_own(0, &current, "alice"), None);
+    }
+}
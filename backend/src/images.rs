@@ -0,0 +1,428 @@
+//! Content-defined chunking and dedup store for image uploads.
+//!
+//! `SendImage` used to rebroadcast a whole base64 data URI to every client
+//! through the broadcast channel - a memory and bandwidth hazard with "no
+//! size limit". Instead, [`ingest`] decodes the data URI and splits the raw
+//! bytes into variable-length chunks with a gear-hash rolling boundary (the
+//! same trick content-addressed storage systems use), storing each chunk
+//! once in a content-addressed map keyed by its hash. Only a small
+//! [`ImageManifest`] of chunk hashes goes out over the broadcast channel;
+//! clients fetch the bytes for each hash through `GET /image/{hash}`, and a
+//! repeated or near-identical upload dedups automatically since it produces
+//! mostly the same chunk hashes as before.
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::Instant;
+use tokio::sync::Mutex;
+
+/// Smallest a content-defined chunk is allowed to be.
+const MIN_CHUNK_SIZE: usize = 2 * 1024;
+
+/// Largest a content-defined chunk is allowed to be, regardless of what the
+/// rolling hash says - this is what keeps a long run of low-entropy bytes
+/// (e.g. a solid-color PNG) from producing one giant chunk.
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Mask applied to the rolling hash to decide a chunk boundary. With the low
+/// 13 bits required to be zero, a boundary occurs on average every 2^13 =
+/// 8192 bytes, landing in the requested ~8-16 KB average range once the
+/// min/max clamps above are folded in.
+const BOUNDARY_MASK: u64 = (1 << 13) - 1;
+
+/// Reject an upload outright past this decoded size.
+const MAX_IMAGE_BYTES: usize = 8 * 1024 * 1024;
+
+/// Reject an upload that would produce more than this many chunks, so a
+/// pathological input can't blow up the store's chunk count instead of its
+/// byte count.
+const MAX_CHUNKS_PER_IMAGE: usize = 2048;
+
+/// Total bytes of chunk data kept in [`ImageStore`] before the
+/// least-recently-fetched chunks are evicted to make room. Per-upload caps
+/// above only bound a single `ingest`; without this, a long-running server
+/// that sees many distinct (non-duplicate) uploads over days would still
+/// grow the store without bound - the exact hazard dedup was meant to
+/// eliminate, just moved from per-broadcast to cumulative.
+const MAX_STORE_BYTES: usize = 512 * 1024 * 1024;
+
+/// Why an uploaded image was rejected.
+#[derive(Debug)]
+pub(crate) enum ImageError {
+    /// Not a `data:<mime>;base64,<payload>` URI, or the payload didn't
+    /// base64-decode.
+    InvalidDataUri,
+    /// Decoded past `MAX_IMAGE_BYTES`.
+    TooLarge,
+    /// Chunked into more than `MAX_CHUNKS_PER_IMAGE` pieces.
+    TooManyChunks,
+}
+
+/// The manifest broadcast to clients in place of the raw image bytes: enough
+/// to fetch every chunk, in order, and reassemble it.
+#[derive(Debug, Clone, serde::Serialize)]
+pub(crate) struct ImageManifest {
+    pub(crate) image_id: String,
+    pub(crate) mime: String,
+    pub(crate) total_size: usize,
+    pub(crate) chunk_hashes: Vec<String>,
+}
+
+/// One chunk's bytes plus when it was last read or written, so the store can
+/// tell which chunks are coldest once it needs to evict.
+struct StoredChunk {
+    bytes: std::sync::Arc<[u8]>,
+    last_used: Instant,
+}
+
+/// The guts of [`ImageStore`]: the chunk map plus the running total of bytes
+/// it holds, kept behind one lock so the total can never drift out of sync
+/// with the map it's describing.
+#[derive(Default)]
+struct StoreInner {
+    chunks: HashMap<String, StoredChunk>,
+    total_bytes: usize,
+}
+
+/// Content-addressed store of image chunks, shared across connections.
+pub(crate) struct ImageStore {
+    inner: Mutex<StoreInner>,
+}
+
+impl ImageStore {
+    pub(crate) fn new() -> Self {
+        ImageStore {
+            inner: Mutex::new(StoreInner::default()),
+        }
+    }
+
+    /// Decode a data URI, split it into content-defined chunks, store any
+    /// that aren't already present, and return the resulting manifest.
+    pub(crate) async fn ingest(&self, data_uri: &str) -> Result<ImageManifest, ImageError> {
+        let decoded = decode_data_uri(data_uri).ok_or(ImageError::InvalidDataUri)?;
+        if decoded.bytes.len() > MAX_IMAGE_BYTES {
+            return Err(ImageError::TooLarge);
+        }
+
+        let pieces = content_defined_chunks(&decoded.bytes);
+        if pieces.len() > MAX_CHUNKS_PER_IMAGE {
+            return Err(ImageError::TooManyChunks);
+        }
+
+        let mut chunk_hashes = Vec::with_capacity(pieces.len());
+        let now = Instant::now();
+        {
+            let mut inner = self.inner.lock().await;
+            for piece in &pieces {
+                let hash = chunk_hash(piece);
+                match inner.chunks.get_mut(&hash) {
+                    Some(existing) => existing.last_used = now,
+                    None => {
+                        inner.total_bytes += piece.len();
+                        inner.chunks.insert(
+                            hash.clone(),
+                            StoredChunk {
+                                bytes: std::sync::Arc::from(*piece),
+                                last_used: now,
+                            },
+                        );
+                    }
+                }
+                chunk_hashes.push(hash);
+            }
+
+            inner.evict_least_recently_used();
+        }
+
+        Ok(ImageManifest {
+            image_id: chunk_hash(&decoded.bytes),
+            mime: decoded.mime,
+            total_size: decoded.bytes.len(),
+            chunk_hashes,
+        })
+    }
+
+    /// Fetch one chunk's bytes by content hash, for `GET /image/{hash}`,
+    /// bumping it to most-recently-used so an in-flight download doesn't get
+    /// evicted for being cold while it's actively being fetched.
+    pub(crate) async fn get_chunk(&self, hash: &str) -> Option<std::sync::Arc<[u8]>> {
+        let mut inner = self.inner.lock().await;
+        let chunk = inner.chunks.get_mut(hash)?;
+        chunk.last_used = Instant::now();
+        Some(chunk.bytes.clone())
+    }
+}
+
+impl StoreInner {
+    /// Evict the least-recently-used chunks until the store is back under
+    /// [`MAX_STORE_BYTES`], so genuinely distinct uploads accumulated over
+    /// the life of the process can't grow it without bound.
+    fn evict_least_recently_used(&mut self) {
+        while self.total_bytes > MAX_STORE_BYTES {
+            let Some(oldest_hash) = self
+                .chunks
+                .iter()
+                .min_by_key(|(_, chunk)| chunk.last_used)
+                .map(|(hash, _)| hash.clone())
+            else {
+                break;
+            };
+            if let Some(chunk) = self.chunks.remove(&oldest_hash) {
+                self.total_bytes -= chunk.bytes.len();
+            }
+        }
+    }
+}
+
+/// A decoded data URI.
+struct DecodedImage {
+    mime: String,
+    bytes: Vec<u8>,
+}
+
+/// Parse and base64-decode a `data:<mime>;base64,<payload>` URI.
+fn decode_data_uri(data_uri: &str) -> Option<DecodedImage> {
+    let rest = data_uri.strip_prefix("data:")?;
+    let (meta, payload) = rest.split_once(',')?;
+    if !meta.ends_with(";base64") {
+        return None;
+    }
+    let mime = meta.trim_end_matches(";base64").to_string();
+    let bytes = base64_decode(payload)?;
+    Some(DecodedImage { mime, bytes })
+}
+
+/// Decode standard base64 (RFC 4648), ignoring embedded whitespace.
+fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    fn value(byte: u8) -> Option<u8> {
+        match byte {
+            b'A'..=b'Z' => Some(byte - b'A'),
+            b'a'..=b'z' => Some(byte - b'a' + 26),
+            b'0'..=b'9' => Some(byte - b'0' + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let cleaned: Vec<u8> = input.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    let payload = cleaned
+        .strip_suffix(b"==")
+        .or_else(|| cleaned.strip_suffix(b"="))
+        .unwrap_or(&cleaned);
+
+    let mut out = Vec::with_capacity(payload.len() / 4 * 3);
+    for group in payload.chunks(4) {
+        let vals: Vec<u8> = group.iter().map(|&b| value(b)).collect::<Option<_>>()?;
+        match vals.len() {
+            4 => {
+                out.push((vals[0] << 2) | (vals[1] >> 4));
+                out.push((vals[1] << 4) | (vals[2] >> 2));
+                out.push((vals[2] << 6) | vals[3]);
+            }
+            3 => {
+                out.push((vals[0] << 2) | (vals[1] >> 4));
+                out.push((vals[1] << 4) | (vals[2] >> 2));
+            }
+            2 => {
+                out.push((vals[0] << 2) | (vals[1] >> 4));
+            }
+            _ => return None,
+        }
+    }
+    Some(out)
+}
+
+/// Split `data` into content-defined chunks using a gear-hash rolling
+/// boundary, so inserting or deleting bytes near the start only shifts the
+/// chunk boundaries that are actually affected instead of every boundary
+/// after that point (unlike fixed-size slicing).
+fn content_defined_chunks(data: &[u8]) -> Vec<&[u8]> {
+    if data.is_empty() {
+        return Vec::new();
+    }
+
+    let table = gear_table();
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut hash: u64 = 0;
+
+    for i in 0..data.len() {
+        hash = (hash << 1).wrapping_add(table[data[i] as usize]);
+        let size = i - start + 1;
+        if size >= MAX_CHUNK_SIZE || (size >= MIN_CHUNK_SIZE && hash & BOUNDARY_MASK == 0) {
+            chunks.push(&data[start..=i]);
+            start = i + 1;
+            hash = 0;
+        }
+    }
+    if start < data.len() {
+        chunks.push(&data[start..]);
+    }
+    chunks
+}
+
+/// A fixed table of pseudo-random values the gear hash mixes in one byte at
+/// a time, generated once from a fixed seed via splitmix64 so it's the same
+/// on every run (a real random table would make the same upload chunk
+/// differently across server restarts, defeating dedup).
+fn gear_table() -> &'static [u64; 256] {
+    static TABLE: OnceLock<[u64; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = [0u64; 256];
+        let mut seed: u64 = 0x9E3779B97F4A7C15;
+        for slot in table.iter_mut() {
+            seed = seed.wrapping_add(0x9E3779B97F4A7C15);
+            let mut z = seed;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+            *slot = z ^ (z >> 31);
+        }
+        table
+    })
+}
+
+/// Content hash used both to key chunks in the store and to derive an
+/// image's id from its full decoded bytes.
+///
+/// A single 64-bit `DefaultHasher` digest isn't a safe key for a dedup
+/// store: `ingest` silently keeps whichever chunk got there first on a
+/// collision, so two genuinely different chunks colliding would mean one
+/// permanently shadows the other with no way to detect it. Combining two
+/// independently-seeded 64-bit hashes into one 128-bit digest squares the
+/// odds of an undetected collision instead of relying on one alone.
+fn chunk_hash(bytes: &[u8]) -> String {
+    use std::hash::{Hash, Hasher};
+
+    let mut first = std::collections::hash_map::DefaultHasher::new();
+    bytes.hash(&mut first);
+
+    // Seed the second hasher with an arbitrary constant before feeding it
+    // the same bytes, so its internal state - and therefore its digest -
+    // diverges from the first hasher's even though both start from the same
+    // fixed DefaultHasher keys.
+    let mut second = std::collections::hash_map::DefaultHasher::new();
+    0x9E3779B97F4A7C15u64.hash(&mut second);
+    bytes.hash(&mut second);
+
+    format!("{:016x}{:016x}", first.finish(), second.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn content_defined_chunks_reassemble_to_the_input() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 256) as u8).collect();
+        let pieces = content_defined_chunks(&data);
+        let reassembled: Vec<u8> = pieces.concat();
+        assert_eq!(reassembled, data);
+    }
+
+    #[test]
+    fn content_defined_chunks_respects_min_and_max_size() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 256) as u8).collect();
+        let pieces = content_defined_chunks(&data);
+
+        assert!(pieces.len() > 1, "input should split into more than one chunk");
+        for (i, piece) in pieces.iter().enumerate() {
+            assert!(piece.len() <= MAX_CHUNK_SIZE, "chunk {i} exceeds MAX_CHUNK_SIZE");
+            // Only the final chunk may be shorter than MIN_CHUNK_SIZE, since
+            // there just might not be enough trailing bytes left to reach it.
+            if i + 1 < pieces.len() {
+                assert!(piece.len() >= MIN_CHUNK_SIZE, "chunk {i} is below MIN_CHUNK_SIZE");
+            }
+        }
+    }
+
+    #[test]
+    fn content_defined_chunks_of_empty_input_is_empty() {
+        assert!(content_defined_chunks(&[]).is_empty());
+    }
+
+    #[test]
+    fn content_defined_chunks_caps_a_low_entropy_run_at_max_chunk_size() {
+        let data = vec![0u8; MAX_CHUNK_SIZE * 3];
+        let pieces = content_defined_chunks(&data);
+        for piece in &pieces {
+            assert!(piece.len() <= MAX_CHUNK_SIZE);
+        }
+    }
+
+    #[test]
+    fn decode_data_uri_round_trips_base64() {
+        let decoded = decode_data_uri("data:image/png;base64,aGVsbG8=").unwrap();
+        assert_eq!(decoded.mime, "image/png");
+        assert_eq!(decoded.bytes, b"hello");
+    }
+
+    #[test]
+    fn decode_data_uri_rejects_non_base64_uris() {
+        assert!(decode_data_uri("data:image/png,plain-text").is_none());
+        assert!(decode_data_uri("not-a-data-uri").is_none());
+    }
+
+    #[test]
+    fn chunk_hash_is_deterministic_and_distinguishes_inputs() {
+        assert_eq!(chunk_hash(b"hello"), chunk_hash(b"hello"));
+        assert_ne!(chunk_hash(b"hello"), chunk_hash(b"world"));
+    }
+
+    fn stored(bytes: &[u8], last_used: Instant) -> StoredChunk {
+        StoredChunk {
+            bytes: std::sync::Arc::from(bytes),
+            last_used,
+        }
+    }
+
+    #[test]
+    fn evict_least_recently_used_stays_under_budget() {
+        let now = Instant::now();
+        let mut inner = StoreInner::default();
+        for i in 0..4u8 {
+            let bytes = vec![i; MAX_STORE_BYTES / 2];
+            inner.total_bytes += bytes.len();
+            inner.chunks.insert(i.to_string(), stored(&bytes, now));
+        }
+
+        inner.evict_least_recently_used();
+
+        assert!(inner.total_bytes <= MAX_STORE_BYTES);
+    }
+
+    #[test]
+    fn evict_least_recently_used_drops_the_coldest_chunk_first() {
+        let now = Instant::now();
+        let mut inner = StoreInner::default();
+        inner
+            .chunks
+            .insert("old".to_string(), stored(&vec![0u8; MAX_STORE_BYTES], now));
+        inner.chunks.insert(
+            "new".to_string(),
+            stored(&vec![0u8; MAX_STORE_BYTES], now + Duration::from_secs(1)),
+        );
+        inner.total_bytes = MAX_STORE_BYTES * 2;
+
+        inner.evict_least_recently_used();
+
+        assert!(!inner.chunks.contains_key("old"));
+        assert!(inner.chunks.contains_key("new"));
+    }
+
+    #[test]
+    fn evict_least_recently_used_is_a_no_op_under_budget() {
+        let now = Instant::now();
+        let mut inner = StoreInner::default();
+        inner
+            .chunks
+            .insert("only".to_string(), stored(&[0u8; 16], now));
+        inner.total_bytes = 16;
+
+        inner.evict_least_recently_used();
+
+        assert!(inner.chunks.contains_key("only"));
+        assert_eq!(inner.total_bytes, 16);
+    }
+}
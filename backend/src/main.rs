@@ -4,11 +4,12 @@
 //! pixel art canvas where users worldwide can paint together in real-time.
 
 use axum::{
+    body::Body,
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        ConnectInfo, State,
+        ConnectInfo, Path, Query, State,
     },
-    http::StatusCode,
+    http::{header, StatusCode},
     response::IntoResponse,
     routing::get,
     Router,
@@ -16,30 +17,31 @@ use axum::{
 use futures::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
     net::SocketAddr,
     sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tokio::sync::{broadcast, RwLock};
+use tokio::sync::{broadcast, mpsc, RwLock};
 use tower_http::cors::{Any, CorsLayer};
-use tracing::{info, warn, error};
+use tracing::{info, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod chunks;
+mod history;
+mod images;
+mod peering;
+mod persistence;
+mod rate_limit;
+mod shutdown;
+
 // ============================================================================
 // CONSTANTS
 // ============================================================================
 
 /// Grid dimensions
-const GRID_WIDTH: usize = 120;
-const GRID_HEIGHT: usize = 180;
-const GRID_SIZE: usize = GRID_WIDTH * GRID_HEIGHT; // 21,600 pixels
-
-/// Cooldown time in seconds
-const COOLDOWN_SECONDS: u64 = 5;
-
-/// Backup interval in seconds
-const BACKUP_INTERVAL_SECS: u64 = 60;
+pub(crate) const GRID_WIDTH: usize = 120;
+pub(crate) const GRID_HEIGHT: usize = 180;
+pub(crate) const GRID_SIZE: usize = GRID_WIDTH * GRID_HEIGHT; // 21,600 pixels
 
 /// Default pixel color (transparent/empty)
 const DEFAULT_COLOR: &str = "#1a1a2e";
@@ -47,19 +49,16 @@ const DEFAULT_COLOR: &str = "#1a1a2e";
 /// Broadcast channel capacity
 const BROADCAST_CAPACITY: usize = 1024;
 
-/// Backup file path
-const BACKUP_FILE: &str = "../data/backup.json";
-
 // ============================================================================
 // DATA STRUCTURES
 // ============================================================================
 
 /// A single pixel on the canvas
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct Pixel {
-    color: String,
-    last_updated: u64,
-    modifier_id: String,
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Pixel {
+    pub(crate) color: String,
+    pub(crate) last_updated: u64,
+    pub(crate) modifier_id: String,
 }
 
 impl Default for Pixel {
@@ -73,55 +72,108 @@ impl Default for Pixel {
 }
 
 /// The main application state
-struct AppState {
+pub(crate) struct AppState {
     /// 1D vector representing the 2D grid (y * WIDTH + x)
-    grid: Vec<Pixel>,
-    /// Map of user IDs to their last paint timestamp (for cooldown)
-    user_cooldowns: HashMap<String, u64>,
+    pub(crate) grid: Vec<Pixel>,
     /// Current number of connected users
     online_count: usize,
     /// Tree shape mask (true = paintable area)
-    tree_mask: Vec<bool>,
+    pub(crate) tree_mask: Vec<bool>,
+    /// Number of write-ahead log events replayed on top of the last snapshot
+    /// at startup (see the `persistence` module). Exposed via `/stats`.
+    pub(crate) replay_length: usize,
+    /// Pixel edits accumulated per chunk since the last coalescing tick (see
+    /// the `chunks` module). Drained into `UPDATE_CHUNK` broadcasts.
+    pub(crate) pending_chunk_edits:
+        std::collections::HashMap<usize, std::collections::HashMap<usize, String>>,
+    /// Bounded per-pixel version stacks backing `ClientMessage::Undo` (see
+    /// the `history` module).
+    pub(crate) pixel_history: history::PixelHistory,
 }
 
 impl AppState {
     fn new() -> Self {
         let grid = vec![Pixel::default(); GRID_SIZE];
         let tree_mask = generate_tree_mask();
-        
+
         AppState {
             grid,
-            user_cooldowns: HashMap::new(),
             online_count: 0,
             tree_mask,
+            replay_length: 0,
+            pending_chunk_edits: std::collections::HashMap::new(),
+            pixel_history: history::PixelHistory::new(),
         }
     }
-    
-    /// Load state from backup file if it exists
+
+    /// Load state from the latest snapshot, then replay the write-ahead log
+    /// tail on top of it (see `persistence::load_and_replay`). The snapshot
+    /// and each logged event are merged in pixel-by-pixel via `merge_pixel`
+    /// rather than applied wholesale, so a newer in-memory state is never
+    /// clobbered by stale data.
     fn load_from_backup() -> Self {
         let mut state = Self::new();
-        
-        if let Ok(data) = std::fs::read_to_string(BACKUP_FILE) {
-            if let Ok(grid) = serde_json::from_str::<Vec<Pixel>>(&data) {
-                if grid.len() == GRID_SIZE {
-                    state.grid = grid;
-                    info!("Loaded grid state from backup file");
-                }
-            }
-        }
-        
+        let (grid, replay_length) = persistence::load_and_replay();
+        state.grid = grid;
+        state.replay_length = replay_length;
         state
     }
 }
 
+// ============================================================================
+// CRDT MERGE LOGIC
+// ============================================================================
+
+/// Merge two versions of the same pixel into one, LWW-register style.
+///
+/// The pixel with the greater `last_updated` wins. Ties are broken first by
+/// the lexicographically greater `modifier_id`, then by the lexicographically
+/// greater `color`, so that the result depends only on the two inputs and not
+/// on which one is "a" and which is "b". This makes `merge_pixel` commutative,
+/// associative and idempotent, which is what lets divergent grids (a running
+/// state and a loaded backup, or eventually two server replicas) converge to
+/// the same result no matter the order updates are observed in.
+pub(crate) fn merge_pixel(a: &Pixel, b: &Pixel) -> Pixel {
+    let winner = match a.last_updated.cmp(&b.last_updated) {
+        std::cmp::Ordering::Greater => a,
+        std::cmp::Ordering::Less => b,
+        std::cmp::Ordering::Equal => match a.modifier_id.cmp(&b.modifier_id) {
+            std::cmp::Ordering::Greater => a,
+            std::cmp::Ordering::Less => b,
+            std::cmp::Ordering::Equal => {
+                if a.color >= b.color {
+                    a
+                } else {
+                    b
+                }
+            }
+        },
+    };
+    winner.clone()
+}
+
 /// Shared state type
-type SharedState = Arc<RwLock<AppState>>;
+pub(crate) type SharedState = Arc<RwLock<AppState>>;
 
 /// Combined application context for Axum state
 #[derive(Clone)]
 struct AppContext {
     state: SharedState,
     tx: broadcast::Sender<ServerMessage>,
+    /// Outbound channel to every peer connection's writer task. Locally
+    /// applied paints (and paints learned from one peer) are published here
+    /// so they fan out across the full mesh.
+    peer_tx: broadcast::Sender<peering::PeerMessage>,
+    /// Write-ahead log that every accepted paint is appended to.
+    wal: Arc<persistence::WalWriter>,
+    /// Per-user token-bucket rate limiter for paint and chat traffic.
+    rate_limiter: Arc<rate_limit::RateLimiter>,
+    /// Content-addressed, deduplicated store backing uploaded images.
+    images: Arc<images::ImageStore>,
+    /// Flipped once the process starts shutting down; checked by
+    /// `ws_handler` to stop accepting new connections (see the `shutdown`
+    /// module).
+    shutdown: shutdown::Shutdown,
 }
 
 // ============================================================================
@@ -138,34 +190,62 @@ enum ClientMessage {
     SendMessage { text: String },
     #[serde(rename = "SEND_IMAGE")]
     SendImage { data: String },
+    /// Rewind a pixel the sender last painted to its previous version (see
+    /// the `history` module). A no-op if someone else has painted over it
+    /// since, or if there's no prior version left to pop.
+    #[serde(rename = "UNDO")]
+    Undo { index: usize },
 }
 
 /// Outgoing message to client
 #[derive(Debug, Clone, Serialize)]
 #[serde(tag = "type", content = "payload")]
-enum ServerMessage {
-    #[serde(rename = "INITIAL_STATE")]
-    InitialState { 
-        grid: Vec<PixelData>,
-        online_count: usize,
+pub(crate) enum ServerMessage {
+    /// One chunk's full pixel colors and tree-mask slice, streamed
+    /// sequentially on connect so the client can render progressively and
+    /// cache the mask per chunk instead of resending it with every pixel.
+    #[serde(rename = "CHUNK_STATE")]
+    ChunkState {
+        chunk_id: usize,
+        colors: Vec<String>,
         tree_mask: Vec<bool>,
     },
-    #[serde(rename = "UPDATE_PIXEL")]
-    UpdatePixel { index: usize, color: String },
+    /// The pixel edits that landed in one chunk since the last coalescing
+    /// tick (see the `chunks` module), sent as a single message instead of
+    /// one broadcast per pixel.
+    #[serde(rename = "UPDATE_CHUNK")]
+    UpdateChunk {
+        chunk_id: usize,
+        edits: Vec<chunks::PixelEdit>,
+    },
     #[serde(rename = "UPDATE_COUNT")]
     UpdateCount { count: usize },
     #[serde(rename = "FALLING_ITEM")]
-    FallingItem { 
-        item_type: String,  // "text" or "image"
-        content: String,    // text content or base64 image
+    FallingItem {
+        item_type: String,  // "text"
+        content: String,    // text content
         x_position: f32,    // 0.0 - 1.0 random position
     },
-}
-
-/// Simplified pixel data for initial state
-#[derive(Debug, Clone, Serialize)]
-struct PixelData {
-    color: String,
+    /// An uploaded image, broadcast as a manifest of chunk hashes instead of
+    /// the raw bytes (see the `images` module) - clients fetch each chunk
+    /// via `GET /image/{hash}` and reassemble them in order.
+    #[serde(rename = "FALLING_IMAGE")]
+    FallingImage {
+        manifest: images::ImageManifest,
+        x_position: f32,
+    },
+    #[serde(rename = "RATE_LIMITED")]
+    RateLimited { retry_after_ms: u64 },
+    /// A single pixel restored by `ClientMessage::Undo`, broadcast right
+    /// away instead of folded into the next `UPDATE_CHUNK` tick so the
+    /// painter sees their undo land immediately.
+    #[serde(rename = "UPDATE_PIXEL")]
+    UpdatePixel { index: usize, color: String },
+    /// Sent once to every connected client right before the process starts
+    /// its graceful shutdown, so clients can warn users before the socket
+    /// drops out from under them.
+    #[serde(rename = "SERVER_SHUTTING_DOWN")]
+    ServerShuttingDown,
 }
 
 // ============================================================================
@@ -279,42 +359,64 @@ async fn ws_handler(
     State(ctx): State<AppContext>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
 ) -> impl IntoResponse {
+    if ctx.shutdown.is_shutting_down() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "server is shutting down").into_response();
+    }
+
     let user_id = hash_ip(&addr);
     info!("New WebSocket connection from {}", addr);
-    ws.on_upgrade(move |socket| handle_socket(socket, ctx.state, ctx.tx, user_id))
+    ws.on_upgrade(move |socket| handle_socket(socket, ctx, user_id))
+        .into_response()
 }
 
-async fn handle_socket(
-    socket: WebSocket,
-    state: SharedState,
-    tx: broadcast::Sender<ServerMessage>,
-    user_id: String,
-) {
+/// Upgrade a peer-to-peer federation connection (see the `peering` module).
+async fn peer_ws_handler(
+    ws: WebSocketUpgrade,
+    State(ctx): State<AppContext>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> impl IntoResponse {
+    if ctx.shutdown.is_shutting_down() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "server is shutting down").into_response();
+    }
+
+    info!("New peer connection from {}", addr);
+    let shutdown_rx = ctx.shutdown.subscribe();
+    ws.on_upgrade(move |socket| {
+        peering::handle_peer_socket(socket, ctx.state, ctx.peer_tx, ctx.wal, shutdown_rx)
+    })
+    .into_response()
+}
+
+async fn handle_socket(socket: WebSocket, ctx: AppContext, user_id: String) {
     let (mut sender, mut receiver) = socket.split();
-    
+
     // Increment online count
     {
-        let mut state = state.write().await;
+        let mut state = ctx.state.write().await;
         state.online_count += 1;
         let count = state.online_count;
-        let _ = tx.send(ServerMessage::UpdateCount { count });
+        let _ = ctx.tx.send(ServerMessage::UpdateCount { count });
         info!("User {} connected. Online: {}", user_id, count);
     }
-    
-    // Send initial state
+
+    // Stream chunk state progressively, so the client can start rendering
+    // before the whole grid has arrived, then let it know the current
+    // online count (it joined after the broadcast above already went out).
     {
-        let state = state.read().await;
-        let grid: Vec<PixelData> = state.grid.iter()
-            .map(|p| PixelData { color: p.color.clone() })
-            .collect();
-        
-        let initial_msg = ServerMessage::InitialState {
-            grid,
-            online_count: state.online_count,
-            tree_mask: state.tree_mask.clone(),
+        let state = ctx.state.read().await;
+        for chunk_id in 0..chunks::CHUNK_COUNT {
+            let msg = chunks::chunk_state_message(&state.grid, &state.tree_mask, chunk_id);
+            if let Ok(json) = serde_json::to_string(&msg) {
+                if sender.send(Message::Text(json)).await.is_err() {
+                    return;
+                }
+            }
+        }
+
+        let count_msg = ServerMessage::UpdateCount {
+            count: state.online_count,
         };
-        
-        if let Ok(json) = serde_json::to_string(&initial_msg) {
+        if let Ok(json) = serde_json::to_string(&count_msg) {
             if sender.send(Message::Text(json)).await.is_err() {
                 return;
             }
@@ -322,35 +424,48 @@ async fn handle_socket(
     }
     
     // Subscribe to broadcast channel
-    let mut rx = tx.subscribe();
-    
-    // Clone user_id for the receive task
+    let mut rx = ctx.tx.subscribe();
+
+    // Channel for replies meant only for this client (e.g. rate-limit
+    // notices), kept separate from the broadcast channel everyone shares.
+    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel::<ServerMessage>();
+
+    // Clone user_id and ctx for the receive task
     let user_id_clone = user_id.clone();
-    let state_clone = state.clone();
-    let tx_clone = tx.clone();
-    
-    // Spawn task to forward broadcast messages to this client
+    let ctx_clone = ctx.clone();
+    let reply_tx_clone = reply_tx.clone();
+
+    // Spawn task to forward broadcast messages and direct replies to this client
     let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            if let Ok(json) = serde_json::to_string(&msg) {
-                if sender.send(Message::Text(json)).await.is_err() {
-                    break;
+        loop {
+            tokio::select! {
+                msg = rx.recv() => {
+                    let Ok(msg) = msg else { break };
+                    if let Ok(json) = serde_json::to_string(&msg) {
+                        if sender.send(Message::Text(json)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                msg = reply_rx.recv() => {
+                    let Some(msg) = msg else { break };
+                    if let Ok(json) = serde_json::to_string(&msg) {
+                        if sender.send(Message::Text(json)).await.is_err() {
+                            break;
+                        }
+                    }
                 }
             }
         }
     });
-    
+
     // Handle incoming messages from this client
     let mut recv_task = tokio::spawn(async move {
         while let Some(Ok(msg)) = receiver.next().await {
             if let Message::Text(text) = msg {
                 if let Ok(client_msg) = serde_json::from_str::<ClientMessage>(&text) {
-                    handle_client_message(
-                        client_msg,
-                        &state_clone,
-                        &tx_clone,
-                        &user_id_clone,
-                    ).await;
+                    handle_client_message(client_msg, &ctx_clone, &reply_tx_clone, &user_id_clone)
+                        .await;
                 }
             }
         }
@@ -364,52 +479,89 @@ async fn handle_socket(
     
     // Decrement online count
     {
-        let mut state = state.write().await;
+        let mut state = ctx.state.write().await;
         state.online_count = state.online_count.saturating_sub(1);
         let count = state.online_count;
-        let _ = tx.send(ServerMessage::UpdateCount { count });
+        let _ = ctx.tx.send(ServerMessage::UpdateCount { count });
         info!("User {} disconnected. Online: {}", user_id, count);
     }
 }
 
 async fn handle_client_message(
     msg: ClientMessage,
-    state: &SharedState,
-    tx: &broadcast::Sender<ServerMessage>,
+    ctx: &AppContext,
+    reply_tx: &mpsc::UnboundedSender<ServerMessage>,
     user_id: &str,
 ) {
+    // Paint, SendMessage, SendImage and Undo all draw from the same
+    // per-user bucket - whichever arrives first when it's empty gets dropped.
+    if let Err(retry_after_ms) = ctx.rate_limiter.try_consume(user_id).await {
+        let _ = reply_tx.send(ServerMessage::RateLimited { retry_after_ms });
+        return;
+    }
+
     match msg {
         ClientMessage::Paint { index, color } => {
             let now = current_timestamp();
-            
+
             // Validate inputs
             if index >= GRID_SIZE {
                 warn!("Invalid index {} from user {}", index, user_id);
                 return;
             }
-            
+
             if !is_valid_hex_color(&color) {
                 warn!("Invalid color {} from user {}", color, user_id);
                 return;
             }
-            
-            let mut state = state.write().await;
-            
-            // Check tree mask
-            if !state.tree_mask[index] {
-                // Position is outside the tree - silently ignore
-                return;
+
+            {
+                let mut state = ctx.state.write().await;
+
+                // Check tree mask
+                if !state.tree_mask[index] {
+                    // Position is outside the tree - silently ignore
+                    return;
+                }
+
+                // Stash the version being overwritten so it can be undone.
+                let previous = state.grid[index].clone();
+                state.pixel_history.push(index, previous);
+
+                // Update the pixel. Already past the per-user token bucket
+                // above, so this is the only throttling a paint is subject to.
+                state.grid[index] = Pixel {
+                    color: color.clone(),
+                    last_updated: now,
+                    modifier_id: user_id.to_string(),
+                };
+
+                // Mark the owning chunk dirty; the coalescer broadcasts it
+                // as a single UPDATE_CHUNK on its next tick instead of one
+                // message per pixel.
+                state
+                    .pending_chunk_edits
+                    .entry(chunks::chunk_id_for_index(index))
+                    .or_default()
+                    .insert(index, color.clone());
             }
-            
-            // Update the pixel (NO COOLDOWN - real-time!)
-            state.grid[index] = Pixel {
+
+            // Persist this paint to the write-ahead log. The append is
+            // buffered; it becomes durable at the next periodic fsync.
+            ctx.wal.append(&persistence::LogEvent {
+                index,
                 color: color.clone(),
                 last_updated: now,
                 modifier_id: user_id.to_string(),
-            };
-            
-            // Broadcast the update
-            let _ = tx.send(ServerMessage::UpdatePixel { index, color });
+            }).await;
+
+            // Forward to every peer
+            let _ = ctx.peer_tx.send(peering::PeerMessage::Paint {
+                index,
+                color,
+                modifier_id: user_id.to_string(),
+                last_updated: now,
+            });
         }
         
         ClientMessage::SendMessage { text } => {
@@ -431,7 +583,7 @@ async fn handle_client_message(
             info!("User {} sent message: {}", user_id, text);
             
             // Broadcast falling text
-            let _ = tx.send(ServerMessage::FallingItem {
+            let _ = ctx.tx.send(ServerMessage::FallingItem {
                 item_type: "text".to_string(),
                 content: text,
                 x_position,
@@ -439,54 +591,84 @@ async fn handle_client_message(
         }
         
         ClientMessage::SendImage { data } => {
-            // No size limit - accept any image
-            
             // Basic validation - should start with data URI prefix
             if !data.starts_with("data:image/") {
                 warn!("Invalid image data from user {}", user_id);
                 return;
             }
-            
+
+            // Chunk, dedup and store the decoded bytes; only the resulting
+            // manifest of chunk hashes goes out over the broadcast channel.
+            let manifest = match ctx.images.ingest(&data).await {
+                Ok(manifest) => manifest,
+                Err(e) => {
+                    warn!("Rejected image from user {}: {:?}", user_id, e);
+                    return;
+                }
+            };
+
             // Generate random x position
-            let x_position = (user_id.bytes().fold(0u32, |acc, b| acc.wrapping_add(b as u32)) as f32 
+            let x_position = (user_id.bytes().fold(0u32, |acc, b| acc.wrapping_add(b as u32)) as f32
                 + current_timestamp() as f32) % 100.0 / 100.0;
-            
-            info!("User {} sent an image", user_id);
-            
+
+            info!(
+                "User {} sent an image ({} bytes, {} chunk(s))",
+                user_id,
+                manifest.total_size,
+                manifest.chunk_hashes.len()
+            );
+
             // Broadcast falling image
-            let _ = tx.send(ServerMessage::FallingItem {
-                item_type: "image".to_string(),
-                content: data,
+            let _ = ctx.tx.send(ServerMessage::FallingImage {
+                manifest,
                 x_position,
             });
         }
-    }
-}
-
-// ============================================================================
-// BACKUP SYSTEM
-// ============================================================================
 
-async fn backup_task(state: SharedState) {
-    loop {
-        tokio::time::sleep(tokio::time::Duration::from_secs(BACKUP_INTERVAL_SECS)).await;
-        
-        let grid = {
-            let state = state.read().await;
-            state.grid.clone()
-        };
-        
-        match serde_json::to_string(&grid) {
-            Ok(json) => {
-                if let Err(e) = std::fs::write(BACKUP_FILE, json) {
-                    error!("Failed to write backup: {}", e);
-                } else {
-                    info!("Grid state backed up successfully");
-                }
-            }
-            Err(e) => {
-                error!("Failed to serialize grid: {}", e);
+        ClientMessage::Undo { index } => {
+            if index >= GRID_SIZE {
+                warn!("Invalid undo index {} from user {}", index, user_id);
+                return;
             }
+
+            let restored = {
+                let mut state = ctx.state.write().await;
+                let current = state.grid[index].clone();
+                let Some(previous) = state.pixel_history.pop_own(index, &current, user_id) else {
+                    return;
+                };
+
+                let restored = Pixel {
+                    color: previous.color,
+                    last_updated: current_timestamp(),
+                    modifier_id: previous.modifier_id,
+                };
+                state.grid[index] = restored.clone();
+                restored
+            };
+
+            // Persist and federate the undo exactly like any other paint -
+            // from here on it's indistinguishable from one.
+            ctx.wal.append(&persistence::LogEvent {
+                index,
+                color: restored.color.clone(),
+                last_updated: restored.last_updated,
+                modifier_id: restored.modifier_id.clone(),
+            }).await;
+
+            let _ = ctx.peer_tx.send(peering::PeerMessage::Paint {
+                index,
+                color: restored.color.clone(),
+                modifier_id: restored.modifier_id.clone(),
+                last_updated: restored.last_updated,
+            });
+
+            info!("User {} undid pixel {}", user_id, index);
+
+            let _ = ctx.tx.send(ServerMessage::UpdatePixel {
+                index,
+                color: restored.color,
+            });
         }
     }
 }
@@ -506,10 +688,57 @@ async fn get_stats(State(ctx): State<AppContext>) -> impl IntoResponse {
         "grid_size": GRID_SIZE,
         "grid_width": GRID_WIDTH,
         "grid_height": GRID_HEIGHT,
+        "wal_replay_length": state.replay_length,
     });
     (StatusCode::OK, axum::Json(stats))
 }
 
+/// Serve one content-addressed image chunk by hash, for clients reassembling
+/// an `ImageManifest` received over `/ws`.
+async fn get_image_chunk(
+    State(ctx): State<AppContext>,
+    Path(hash): Path<String>,
+) -> impl IntoResponse {
+    match ctx.images.get_chunk(&hash).await {
+        Some(bytes) => (StatusCode::OK, bytes.to_vec()).into_response(),
+        None => (StatusCode::NOT_FOUND, "chunk not found").into_response(),
+    }
+}
+
+/// Query parameters for `GET /replay`.
+#[derive(Debug, Deserialize)]
+struct ReplayQuery {
+    from: u64,
+    to: u64,
+    #[serde(default = "default_replay_step")]
+    step: usize,
+}
+
+fn default_replay_step() -> usize {
+    25
+}
+
+/// Stream successive grid snapshots reconstructed from the write-ahead log
+/// between `from` and `to` (Unix seconds), one every `step` edits, as
+/// newline-delimited JSON - client-side time-lapse animation of the tree
+/// filling in, without shipping the whole canvas per frame.
+async fn get_replay(Query(query): Query<ReplayQuery>) -> impl IntoResponse {
+    if query.from > query.to {
+        return (StatusCode::BAD_REQUEST, "`from` must not be after `to`").into_response();
+    }
+
+    let log = persistence::read_log();
+    let frames = history::replay_snapshots(&log, GRID_SIZE, query.from, query.to, query.step);
+
+    let body = Body::from_stream(futures::stream::iter(frames.into_iter().map(|frame| {
+        let mut line = serde_json::to_string(&frame).unwrap_or_default();
+        line.push('\n');
+        Ok::<_, std::convert::Infallible>(line)
+    })));
+
+    ([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response()
+}
+
 // ============================================================================
 // MAIN
 // ============================================================================
@@ -527,45 +756,195 @@ async fn main() {
     info!("🎄 The Global Pixel Tree Backend Starting...");
     info!("Grid size: {}x{} = {} pixels", GRID_WIDTH, GRID_HEIGHT, GRID_SIZE);
     
-    // Initialize state (load from backup if available)
+    // Initialize state (load latest snapshot + replay the write-ahead log)
     let state: SharedState = Arc::new(RwLock::new(AppState::load_from_backup()));
-    
+
+    // Open the write-ahead log for the new paints that are about to happen.
+    let wal = Arc::new(
+        persistence::WalWriter::open().expect("failed to open write-ahead log"),
+    );
+
+    // Shutdown signal shared by every background loop and `ws_handler`, plus
+    // the registry that lets `main` wait for those loops to actually exit.
+    let (shutdown, shutdown_rx) = shutdown::Shutdown::new();
+    let mut workers = shutdown::WorkerRegistry::new();
+
+    workers.spawn(persistence::fsync_loop(wal.clone(), shutdown_rx.clone()));
+    workers.spawn(persistence::compaction_loop(
+        state.clone(),
+        wal.clone(),
+        shutdown_rx.clone(),
+    ));
+
     // Create broadcast channel
     let (tx, _rx) = broadcast::channel::<ServerMessage>(BROADCAST_CAPACITY);
-    
+
+    // Create the peer-to-peer federation channel and dial configured peers
+    let (peer_tx, _peer_rx) = broadcast::channel::<peering::PeerMessage>(BROADCAST_CAPACITY);
+    let peers = peering::peers_from_env();
+    if peers.is_empty() {
+        info!("No PEERS configured - running as a single node");
+    } else {
+        info!("Dialing {} peer(s): {:?}", peers.len(), peers);
+        peering::start(
+            peers,
+            state.clone(),
+            peer_tx.clone(),
+            wal.clone(),
+            &mut workers,
+            shutdown_rx.clone(),
+        );
+    }
+
+    // Start the adaptive per-user rate limiter
+    let rate_limiter = Arc::new(rate_limit::RateLimiter::new());
+    workers.spawn(rate_limit::adapter_loop(
+        rate_limiter.clone(),
+        tx.clone(),
+        BROADCAST_CAPACITY,
+        shutdown_rx.clone(),
+    ));
+    workers.spawn(rate_limit::sweeper_loop(
+        rate_limiter.clone(),
+        shutdown_rx.clone(),
+    ));
+
+    // Start the chunk-edit coalescer
+    workers.spawn(chunks::coalesce_loop(
+        state.clone(),
+        tx.clone(),
+        shutdown_rx.clone(),
+    ));
+
+    // Content-addressed store backing deduped image uploads
+    let images = Arc::new(images::ImageStore::new());
+
     // Create application context
     let ctx = AppContext {
         state: state.clone(),
-        tx,
+        tx: tx.clone(),
+        peer_tx,
+        wal: wal.clone(),
+        rate_limiter,
+        images,
+        shutdown: shutdown.clone(),
     };
-    
-    // Start backup task
-    let backup_state = state.clone();
-    tokio::spawn(backup_task(backup_state));
-    
+
     // Configure CORS
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
         .allow_headers(Any);
-    
+
     // Build router
     let app = Router::new()
         .route("/ws", get(ws_handler))
+        .route("/peer", get(peer_ws_handler))
         .route("/health", get(health_check))
         .route("/stats", get(get_stats))
+        .route("/image/:hash", get(get_image_chunk))
+        .route("/replay", get(get_replay))
         .layer(cors)
         .with_state(ctx);
-    
+
     // Start server
     let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
     info!("🚀 Server listening on {}", addr);
-    
+
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(
+    let serve = axum::serve(
         listener,
         app.into_make_service_with_connect_info::<SocketAddr>(),
     )
-    .await
-    .unwrap();
+    .with_graceful_shutdown(shutdown::shutdown_trigger(shutdown, tx));
+
+    // Bounded, not just awaited directly: graceful shutdown only returns
+    // once every already-upgraded connection closes on its own, and a
+    // client that never does (a backgrounded tab, or one that ignores
+    // ServerShuttingDown) would otherwise block this forever, along with the
+    // worker drain and final flush after it. The bound only starts counting
+    // once the shutdown signal has fired.
+    let mut drain_rx = shutdown_rx.clone();
+    let drain_deadline = async move {
+        let _ = drain_rx.changed().await;
+        tokio::time::sleep(Duration::from_secs(shutdown::CONNECTION_DRAIN_TIMEOUT_SECS)).await;
+    };
+    tokio::select! {
+        _ = std::future::IntoFuture::into_future(serve) => {}
+        _ = drain_deadline => {
+            warn!(
+                "Connections didn't drain within {}s, proceeding anyway",
+                shutdown::CONNECTION_DRAIN_TIMEOUT_SECS
+            );
+        }
+    }
+
+    // Every connection has drained (or we gave up waiting); stop the
+    // background loops and force one last flush so nothing accepted right
+    // before shutdown is lost.
+    info!("Waiting for background workers to drain...");
+    workers.drain().await;
+    persistence::final_flush(&state, &wal).await;
+    info!("Shutdown complete");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(color: &str, last_updated: u64, modifier_id: &str) -> Pixel {
+        Pixel {
+            color: color.to_string(),
+            last_updated,
+            modifier_id: modifier_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn merge_pixel_picks_the_greater_last_updated() {
+        let older = pixel("#111111", 1, "a");
+        let newer = pixel("#222222", 2, "a");
+        assert_eq!(merge_pixel(&older, &newer), newer);
+        assert_eq!(merge_pixel(&newer, &older), newer);
+    }
+
+    #[test]
+    fn merge_pixel_breaks_last_updated_ties_on_modifier_id() {
+        let a = pixel("#111111", 5, "alice");
+        let b = pixel("#222222", 5, "bob");
+        assert_eq!(merge_pixel(&a, &b), b);
+        assert_eq!(merge_pixel(&b, &a), b);
+    }
+
+    #[test]
+    fn merge_pixel_breaks_full_ties_on_color() {
+        let a = pixel("#111111", 5, "alice");
+        let b = pixel("#222222", 5, "alice");
+        assert_eq!(merge_pixel(&a, &b), b);
+        assert_eq!(merge_pixel(&b, &a), b);
+    }
+
+    #[test]
+    fn merge_pixel_is_commutative() {
+        let a = pixel("#111111", 5, "alice");
+        let b = pixel("#222222", 5, "alice");
+        assert_eq!(merge_pixel(&a, &b), merge_pixel(&b, &a));
+    }
+
+    #[test]
+    fn merge_pixel_is_idempotent() {
+        let a = pixel("#111111", 5, "alice");
+        assert_eq!(merge_pixel(&a, &a), a);
+    }
+
+    #[test]
+    fn merge_pixel_is_associative() {
+        let a = pixel("#111111", 3, "alice");
+        let b = pixel("#222222", 5, "bob");
+        let c = pixel("#333333", 5, "alice");
+
+        let left = merge_pixel(&merge_pixel(&a, &b), &c);
+        let right = merge_pixel(&a, &merge_pixel(&b, &c));
+        assert_eq!(left, right);
+    }
 }
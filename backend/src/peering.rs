@@ -0,0 +1,394 @@
+//! Multi-node federation: full-mesh replication between Pixel Tree instances.
+//!
+//! Each node dials every peer configured via the `PEERS` environment
+//! variable (a comma-separated list of `ws://host:port/peer` URLs) on
+//! startup, and also accepts inbound peer connections on the `/peer` route,
+//! so the mesh doesn't depend on which side happened to dial. Every locally
+//! applied paint is tagged with its origin `modifier_id` and `last_updated`
+//! and published to [`PeerMessage`] subscribers; inbound peer paints are
+//! folded into the local grid through [`crate::merge_pixel`] before being
+//! rebroadcast to this node's own clients and republished to the rest of the
+//! mesh, and appended to the same write-ahead log local paints use so a
+//! federated write is durable within the next fsync rather than only via the
+//! next periodic snapshot. Merging a peer update that turns out to be a
+//! no-op (the remote pixel lost the LWW comparison) stops it there instead
+//! of forwarding it again, which is what keeps replays and re-ordered
+//! deliveries from looping forever around the mesh.
+
+use crate::shutdown::WorkerRegistry;
+use crate::{chunks, merge_pixel, persistence, Pixel, SharedState, GRID_SIZE};
+use axum::extract::ws::{Message as AxumMessage, WebSocket};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::{broadcast, watch};
+use tokio_tungstenite::tungstenite::Message as TungsteniteMessage;
+use tracing::{error, info, warn};
+
+/// How long to wait before redialing a peer after a disconnect or a failed
+/// connection attempt.
+const RECONNECT_DELAY_SECS: u64 = 5;
+
+/// A peer endpoint read from configuration.
+#[derive(Debug, Clone)]
+pub(crate) struct PeerConfig {
+    pub(crate) url: String,
+}
+
+/// Read the peer list from the `PEERS` environment variable, a
+/// comma-separated list of WebSocket URLs (e.g.
+/// `ws://tree-2:3000/peer,ws://tree-3:3000/peer`).
+pub(crate) fn peers_from_env() -> Vec<PeerConfig> {
+    std::env::var("PEERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|url| PeerConfig {
+            url: url.to_string(),
+        })
+        .collect()
+}
+
+/// Wire message exchanged between peers. Distinct from [`ServerMessage`]
+/// because peers need the full LWW metadata (`modifier_id`, `last_updated`)
+/// that client-facing messages never carry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload")]
+pub(crate) enum PeerMessage {
+    /// A single pixel update, carrying the metadata `merge_pixel` needs.
+    Paint {
+        index: usize,
+        color: String,
+        modifier_id: String,
+        last_updated: u64,
+    },
+    /// Sent once right after a connection is established, to trigger the
+    /// other side's one-shot anti-entropy sync.
+    SyncRequest,
+    /// The full grid, sent in reply to a `SyncRequest`.
+    SyncGrid { grid: Vec<Pixel> },
+}
+
+/// Dial every configured peer, reconnecting with a fixed delay if the
+/// connection drops or can't be established. Each dial loop is registered
+/// with `workers` and selects on `shutdown` just like every other
+/// long-running loop (see the `shutdown` module), so a SIGTERM drains peer
+/// connections instead of leaving them to die only when the process exits.
+pub(crate) fn start(
+    peers: Vec<PeerConfig>,
+    state: SharedState,
+    peer_tx: broadcast::Sender<PeerMessage>,
+    wal: Arc<persistence::WalWriter>,
+    workers: &mut WorkerRegistry,
+    shutdown: watch::Receiver<bool>,
+) {
+    for peer in peers {
+        let state = state.clone();
+        let peer_tx = peer_tx.clone();
+        let wal = wal.clone();
+        let shutdown = shutdown.clone();
+        workers.spawn(dial_peer(peer, state, peer_tx, wal, shutdown));
+    }
+}
+
+/// Keep trying to connect to a single peer until `shutdown` fires.
+async fn dial_peer(
+    peer: PeerConfig,
+    state: SharedState,
+    peer_tx: broadcast::Sender<PeerMessage>,
+    wal: Arc<persistence::WalWriter>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    'reconnect: loop {
+        match tokio_tungstenite::connect_async(&peer.url).await {
+            Ok((ws_stream, _)) => {
+                info!("Connected to peer {}", peer.url);
+                let (mut sink, mut stream) = ws_stream.split();
+                let mut peer_rx = peer_tx.subscribe();
+
+                // Kick off the one-shot anti-entropy sync from this side.
+                if let Ok(json) = serde_json::to_string(&PeerMessage::SyncRequest) {
+                    let _ = sink.send(TungsteniteMessage::Text(json)).await;
+                }
+
+                loop {
+                    tokio::select! {
+                        outbound = peer_rx.recv() => {
+                            match outbound {
+                                Ok(msg) => {
+                                    if let Ok(json) = serde_json::to_string(&msg) {
+                                        if sink.send(TungsteniteMessage::Text(json)).await.is_err() {
+                                            break;
+                                        }
+                                    }
+                                }
+                                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                                Err(broadcast::error::RecvError::Closed) => break,
+                            }
+                        }
+                        inbound = stream.next() => {
+                            let Some(Ok(TungsteniteMessage::Text(text))) = inbound else { break };
+                            let Ok(msg) = serde_json::from_str::<PeerMessage>(&text) else { continue };
+                            handle_peer_message(msg, &state, &peer_tx, &wal, &mut sink).await;
+                        }
+                        _ = shutdown.changed() => {
+                            info!("Peer dial loop for {} shutting down", peer.url);
+                            break 'reconnect;
+                        }
+                    }
+                }
+                warn!("Lost connection to peer {}, will retry", peer.url);
+            }
+            Err(e) => {
+                warn!("Failed to connect to peer {}: {}", peer.url, e);
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(tokio::time::Duration::from_secs(RECONNECT_DELAY_SECS)) => {}
+            _ = shutdown.changed() => {
+                info!("Peer dial loop for {} shutting down", peer.url);
+                break;
+            }
+        }
+    }
+}
+
+/// Handle an inbound peer connection accepted on the `/peer` route. Mirrors
+/// `dial_peer`'s session loop, just over an axum `WebSocket` instead of a
+/// tungstenite client stream - including selecting on `shutdown` so an
+/// already-established inbound peer session gets the same graceful-exit path
+/// as every other long-running loop, instead of relying on axum's generic
+/// connection-drain timeout to eventually close it.
+pub(crate) async fn handle_peer_socket(
+    socket: WebSocket,
+    state: SharedState,
+    peer_tx: broadcast::Sender<PeerMessage>,
+    wal: Arc<persistence::WalWriter>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let (mut sink, mut stream) = socket.split();
+    let mut peer_rx = peer_tx.subscribe();
+
+    if let Ok(json) = serde_json::to_string(&PeerMessage::SyncRequest) {
+        let _ = sink.send(AxumMessage::Text(json)).await;
+    }
+
+    loop {
+        tokio::select! {
+            outbound = peer_rx.recv() => {
+                match outbound {
+                    Ok(msg) => {
+                        if let Ok(json) = serde_json::to_string(&msg) {
+                            if sink.send(AxumMessage::Text(json)).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+            inbound = stream.next() => {
+                let Some(Ok(AxumMessage::Text(text))) = inbound else { break };
+                let Ok(msg) = serde_json::from_str::<PeerMessage>(&text) else { continue };
+                handle_peer_message_axum(msg, &state, &peer_tx, &wal, &mut sink).await;
+            }
+            _ = shutdown.changed() => {
+                info!("Inbound peer connection shutting down");
+                break;
+            }
+        }
+    }
+}
+
+/// Apply a single message received from a peer connected over tungstenite
+/// (the dialing side).
+async fn handle_peer_message(
+    msg: PeerMessage,
+    state: &SharedState,
+    peer_tx: &broadcast::Sender<PeerMessage>,
+    wal: &persistence::WalWriter,
+    sink: &mut (impl futures::Sink<TungsteniteMessage> + Unpin),
+) {
+    if let Some(reply) = apply_peer_message(msg, state, peer_tx, wal).await {
+        let _ = sink.send(TungsteniteMessage::Text(reply)).await;
+    }
+}
+
+/// Apply a single message received from a peer connected over axum (the
+/// accepting side).
+async fn handle_peer_message_axum(
+    msg: PeerMessage,
+    state: &SharedState,
+    peer_tx: &broadcast::Sender<PeerMessage>,
+    wal: &persistence::WalWriter,
+    sink: &mut (impl futures::Sink<AxumMessage> + Unpin),
+) {
+    if let Some(reply) = apply_peer_message(msg, state, peer_tx, wal).await {
+        let _ = sink.send(AxumMessage::Text(reply)).await;
+    }
+}
+
+/// Core protocol logic, shared by both transports: apply an inbound
+/// [`PeerMessage`] to local state, append a `LogEvent` to `wal` for every
+/// pixel that actually changed (the same write-ahead log local paints use,
+/// so a federated write is durable within the next fsync instead of only
+/// via the next periodic snapshot), and return a JSON reply to send back
+/// directly to the sender, if any (only `SyncRequest` produces one; replies
+/// that should fan out to the whole mesh go through `peer_tx` instead).
+async fn apply_peer_message(
+    msg: PeerMessage,
+    state: &SharedState,
+    peer_tx: &broadcast::Sender<PeerMessage>,
+    wal: &persistence::WalWriter,
+) -> Option<String> {
+    match msg {
+        PeerMessage::Paint {
+            index,
+            color,
+            modifier_id,
+            last_updated,
+        } => {
+            if index >= GRID_SIZE {
+                warn!("Peer sent out-of-range pixel index {}", index);
+                return None;
+            }
+
+            let incoming = Pixel {
+                color,
+                last_updated,
+                modifier_id,
+            };
+
+            let merged = {
+                let mut state = state.write().await;
+                let current = &state.grid[index];
+                let merged = merge_pixel(current, &incoming);
+
+                // A no-op merge means the peer's version lost; drop it here
+                // instead of rebroadcasting, which bounds the full mesh to
+                // one hop of useless chatter rather than an infinite loop.
+                if pixels_equal(&merged, current) {
+                    return None;
+                }
+
+                state.grid[index] = merged.clone();
+                state
+                    .pending_chunk_edits
+                    .entry(chunks::chunk_id_for_index(index))
+                    .or_default()
+                    .insert(index, merged.color.clone());
+                merged
+            };
+
+            wal.append(&persistence::LogEvent {
+                index,
+                color: merged.color.clone(),
+                last_updated: merged.last_updated,
+                modifier_id: merged.modifier_id.clone(),
+            }).await;
+
+            let _ = peer_tx.send(PeerMessage::Paint {
+                index,
+                color: merged.color,
+                modifier_id: merged.modifier_id,
+                last_updated: merged.last_updated,
+            });
+            None
+        }
+
+        PeerMessage::SyncRequest => {
+            let grid = state.read().await.grid.clone();
+            serde_json::to_string(&PeerMessage::SyncGrid { grid }).ok()
+        }
+
+        PeerMessage::SyncGrid { grid } => {
+            if grid.len() != GRID_SIZE {
+                error!("Peer sent a sync grid of the wrong size ({})", grid.len());
+                return None;
+            }
+
+            let mut changed = Vec::new();
+            {
+                let mut state = state.write().await;
+                for (index, incoming) in grid.into_iter().enumerate() {
+                    let current = &state.grid[index];
+                    let merged = merge_pixel(current, &incoming);
+                    if pixels_equal(&merged, current) {
+                        continue;
+                    }
+                    let color = merged.color.clone();
+                    state.grid[index] = merged.clone();
+                    state
+                        .pending_chunk_edits
+                        .entry(chunks::chunk_id_for_index(index))
+                        .or_default()
+                        .insert(index, color);
+                    changed.push((index, merged));
+                }
+            }
+
+            for (index, merged) in changed {
+                wal.append(&persistence::LogEvent {
+                    index,
+                    color: merged.color,
+                    last_updated: merged.last_updated,
+                    modifier_id: merged.modifier_id,
+                }).await;
+            }
+            None
+        }
+    }
+}
+
+/// Whether a merge was a no-op, i.e. the existing pixel already won.
+fn pixels_equal(a: &Pixel, b: &Pixel) -> bool {
+    a.color == b.color && a.last_updated == b.last_updated && a.modifier_id == b.modifier_id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // `peers_from_env` reads a process-wide environment variable, so tests
+    // touching it must not run concurrently with each other.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    fn pixel(color: &str, last_updated: u64, modifier_id: &str) -> Pixel {
+        Pixel {
+            color: color.to_string(),
+            last_updated,
+            modifier_id: modifier_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn pixels_equal_compares_every_field() {
+        let a = pixel("#111111", 1, "alice");
+        assert!(pixels_equal(&a, &a.clone()));
+        assert!(!pixels_equal(&a, &pixel("#222222", 1, "alice")));
+        assert!(!pixels_equal(&a, &pixel("#111111", 2, "alice")));
+        assert!(!pixels_equal(&a, &pixel("#111111", 1, "bob")));
+    }
+
+    #[test]
+    fn peers_from_env_parses_a_comma_separated_list() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        std::env::set_var("PEERS", " ws://a:3000/peer ,ws://b:3000/peer,");
+        let peers = peers_from_env();
+        std::env::remove_var("PEERS");
+
+        let urls: Vec<&str> = peers.iter().map(|p| p.url.as_str()).collect();
+        assert_eq!(urls, vec!["ws://a:3000/peer", "ws://b:3000/peer"]);
+    }
+
+    #[test]
+    fn peers_from_env_is_empty_when_unset() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        std::env::remove_var("PEERS");
+        assert!(peers_from_env().is_empty());
+    }
+}
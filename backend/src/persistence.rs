@@ -0,0 +1,404 @@
+//! Durable storage for the grid: an append-only write-ahead log plus
+//! periodic compacted snapshots.
+//!
+//! Every accepted paint is appended to [`WAL_FILE`] as soon as it happens
+//! (buffered, with a periodic fsync), so at most a few seconds of paints are
+//! ever at risk on a crash, down from the full `SNAPSHOT_INTERVAL_SECS` window
+//! a 60-second full-grid dump used to expose. A background task still
+//! writes a compacted snapshot of the whole grid every `SNAPSHOT_INTERVAL_SECS`
+//! seconds and, once that succeeds, truncates the log - the snapshot already
+//! covers everything in it. On startup, [`load_and_replay`] loads the newest
+//! snapshot and replays the log tail on top of it through `merge_pixel`, so
+//! recovery lands right where the server left off.
+//!
+//! The entries a compaction truncates aren't thrown away, though: they're
+//! first appended to [`ARCHIVE_FILE`], a second log compaction never
+//! truncates directly. [`read_log`] reads the archive followed by the live
+//! log so `history::replay_snapshots` can reconstruct `/replay` frames from
+//! the full history of the running process, not just whatever's landed
+//! since the last compaction. Since `/replay` is unauthenticated and reads
+//! that whole archive synchronously on every request, the archive itself is
+//! kept bounded to [`MAX_ARCHIVE_EVENTS`] - the oldest entries are dropped
+//! once it grows past that, trading unlimited time-lapse depth for a
+//! bounded per-request cost, the same trade `history::PixelHistory` makes
+//! for undo.
+
+use crate::{merge_pixel, Pixel, SharedState, GRID_SIZE};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+/// Compacted full-grid snapshot path.
+const SNAPSHOT_FILE: &str = "../data/backup.json";
+
+/// Append-only write-ahead log path.
+const WAL_FILE: &str = "../data/wal.jsonl";
+
+/// Append-only archive of write-ahead log entries truncated out of
+/// [`WAL_FILE`] by compaction, so `/replay` can still see them. Trimmed back
+/// to [`MAX_ARCHIVE_EVENTS`] by [`trim_archive`] each time it grows, rather
+/// than kept forever.
+const ARCHIVE_FILE: &str = "../data/wal_archive.jsonl";
+
+/// How often the write-ahead log is flushed and fsynced.
+const FSYNC_INTERVAL_SECS: u64 = 2;
+
+/// How often the grid is snapshotted and the log truncated.
+const SNAPSHOT_INTERVAL_SECS: u64 = 60;
+
+/// Most events kept in [`ARCHIVE_FILE`]. Past this, the oldest are dropped
+/// each time a compaction archives a fresh batch, so neither the archive's
+/// on-disk size nor the cost of `GET /replay` reading it in full grows
+/// without bound over weeks of uptime.
+const MAX_ARCHIVE_EVENTS: usize = 200_000;
+
+/// A single logged paint: enough to reconstruct the `Pixel` it produced.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct LogEvent {
+    pub(crate) index: usize,
+    pub(crate) color: String,
+    pub(crate) last_updated: u64,
+    pub(crate) modifier_id: String,
+}
+
+/// Load the newest snapshot (merged into a fresh default grid) and replay
+/// the write-ahead log tail on top of it. Returns the recovered grid and the
+/// number of log events replayed, the latter exposed via `/stats`.
+pub(crate) fn load_and_replay() -> (Vec<Pixel>, usize) {
+    let mut grid = vec![Pixel::default(); GRID_SIZE];
+
+    if let Ok(data) = std::fs::read_to_string(SNAPSHOT_FILE) {
+        if let Ok(snapshot) = serde_json::from_str::<Vec<Pixel>>(&data) {
+            if snapshot.len() == GRID_SIZE {
+                for (current, loaded) in grid.iter_mut().zip(snapshot.iter()) {
+                    *current = merge_pixel(current, loaded);
+                }
+                info!("Loaded grid snapshot from {}", SNAPSHOT_FILE);
+            }
+        }
+    }
+
+    let mut replayed = 0;
+    if let Ok(data) = std::fs::read_to_string(WAL_FILE) {
+        let events: Vec<LogEvent> = data
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| serde_json::from_str::<LogEvent>(line).ok())
+            .collect();
+        replayed = replay_events(&mut grid, &events);
+        if replayed > 0 {
+            info!("Replayed {} event(s) from the write-ahead log", replayed);
+        }
+    }
+
+    (grid, replayed)
+}
+
+/// Fold `events` onto `grid` in order through `merge_pixel`, skipping any
+/// event whose index is out of range. Returns how many events were actually
+/// applied, split out of `load_and_replay` so the replay logic can be
+/// exercised without touching disk.
+fn replay_events(grid: &mut [Pixel], events: &[LogEvent]) -> usize {
+    let mut applied = 0;
+    for event in events {
+        if event.index >= grid.len() {
+            continue;
+        }
+        let incoming = Pixel {
+            color: event.color.clone(),
+            last_updated: event.last_updated,
+            modifier_id: event.modifier_id.clone(),
+        };
+        grid[event.index] = merge_pixel(&grid[event.index], &incoming);
+        applied += 1;
+    }
+    applied
+}
+
+/// Read and parse every event still on disk, in file order: the archive
+/// (entries truncated out of the write-ahead log by past compactions, trimmed
+/// to [`MAX_ARCHIVE_EVENTS`]) followed by whatever's currently in the live
+/// log. File order is append order, not timestamp order - a peer paint is
+/// appended when it arrives but carries its origin `last_updated`. Unlike
+/// `load_and_replay`, this doesn't fold events into a grid - it hands them
+/// back for `history::replay_snapshots` to walk over a requested time range.
+pub(crate) fn read_log() -> Vec<LogEvent> {
+    [ARCHIVE_FILE, WAL_FILE]
+        .into_iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .flat_map(|data| {
+            data.lines()
+                .filter(|line| !line.trim().is_empty())
+                .filter_map(|line| serde_json::from_str::<LogEvent>(line).ok())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// A buffered, append-only writer for the write-ahead log.
+pub(crate) struct WalWriter {
+    file: Mutex<BufWriter<File>>,
+}
+
+impl WalWriter {
+    /// Open (creating if necessary) the write-ahead log for appending.
+    pub(crate) fn open() -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(WAL_FILE)?;
+        Ok(WalWriter {
+            file: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    /// Append one event. Buffered - call `flush_and_sync` (done periodically
+    /// by `fsync_loop`) to make it durable.
+    pub(crate) async fn append(&self, event: &LogEvent) {
+        let Ok(mut line) = serde_json::to_string(event) else {
+            return;
+        };
+        line.push('\n');
+
+        let mut file = self.file.lock().await;
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            error!("Failed to append to write-ahead log: {}", e);
+        }
+    }
+
+    /// Flush buffered writes and fsync the underlying file.
+    async fn flush_and_sync(&self) {
+        let mut file = self.file.lock().await;
+        if let Err(e) = file.flush() {
+            error!("Failed to flush write-ahead log: {}", e);
+            return;
+        }
+        if let Err(e) = file.get_ref().sync_data() {
+            error!("Failed to fsync write-ahead log: {}", e);
+        }
+    }
+
+    /// Truncate the log to empty after its contents are covered by a fresh
+    /// snapshot.
+    fn truncate(&self) -> std::io::Result<BufWriter<File>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(WAL_FILE)?;
+        Ok(BufWriter::new(file))
+    }
+}
+
+/// Periodically flush and fsync the write-ahead log so buffered events
+/// become durable even between paints. Exits once `shutdown` fires, so it
+/// can be awaited to completion by `shutdown::WorkerRegistry::drain`.
+pub(crate) async fn fsync_loop(
+    wal: std::sync::Arc<WalWriter>,
+    mut shutdown: tokio::sync::watch::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(tokio::time::Duration::from_secs(FSYNC_INTERVAL_SECS)) => {
+                wal.flush_and_sync().await;
+            }
+            _ = shutdown.changed() => {
+                info!("Write-ahead log fsync task shutting down");
+                break;
+            }
+        }
+    }
+}
+
+/// Periodically write a compacted snapshot of the full grid and, once it's
+/// safely on disk, truncate the write-ahead log prefix it now covers. Exits
+/// once `shutdown` fires; the final flush on the way out is instead handled
+/// by `final_flush`, called once from `main` after every connection has
+/// drained.
+pub(crate) async fn compaction_loop(
+    state: SharedState,
+    wal: std::sync::Arc<WalWriter>,
+    mut shutdown: tokio::sync::watch::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(tokio::time::Duration::from_secs(SNAPSHOT_INTERVAL_SECS)) => {
+                compact_once(&state, &wal).await;
+            }
+            _ = shutdown.changed() => {
+                info!("Snapshot compaction task shutting down");
+                break;
+            }
+        }
+    }
+}
+
+/// Write a compacted snapshot of the full grid and, once it's safely on
+/// disk, archive and then truncate the write-ahead log prefix it now covers.
+///
+/// Holds `wal.file`'s lock across the whole read-grid -> write-snapshot ->
+/// flush -> archive -> truncate sequence, not just the truncate itself.
+/// Otherwise a `Paint` could finish (grid updated, then appended to the log)
+/// in the gap between the grid read and the truncate, and that event would
+/// end up in neither the snapshot nor the now-truncated log - lost for good
+/// on a crash before the next compaction. Holding the lock the whole time
+/// instead blocks that `append` until after truncation, so it lands in the
+/// fresh log; landing in both the snapshot and the surviving log is
+/// harmless since `merge_pixel` is idempotent.
+///
+/// The flush before `archive_log` matters on its own: `append` only writes
+/// into the locked `BufWriter`, it doesn't flush, and `fsync_loop` flushes on
+/// its own independent timer. Without flushing here first, `archive_log`'s
+/// `std::fs::read` of `WAL_FILE` would miss whatever's still sitting in the
+/// `BufWriter`'s buffer, and `truncate` swapping in a fresh `BufWriter` would
+/// then drop that buffered data for good - present in neither the archive
+/// nor the truncated live log.
+async fn compact_once(state: &SharedState, wal: &WalWriter) {
+    let mut file = wal.file.lock().await;
+
+    let grid = state.read().await.grid.clone();
+    let json = match serde_json::to_string(&grid) {
+        Ok(json) => json,
+        Err(e) => {
+            error!("Failed to serialize grid snapshot: {}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = std::fs::write(SNAPSHOT_FILE, json) {
+        error!("Failed to write grid snapshot: {}", e);
+        return;
+    }
+
+    // Flush the buffered writer to disk before archive_log reads the file
+    // out from under it, so nothing appended since the last periodic flush
+    // is missed.
+    if let Err(e) = file.flush() {
+        error!("Failed to flush write-ahead log before archiving: {}", e);
+        return;
+    }
+
+    // Append what's about to be truncated to the archive log before
+    // discarding it from the live one, so `read_log` (and therefore
+    // `/replay`) can still see it after compaction.
+    if let Err(e) = archive_log() {
+        error!("Failed to archive write-ahead log before truncation: {}", e);
+        return;
+    }
+
+    match wal.truncate() {
+        Ok(fresh) => {
+            *file = fresh;
+            drop(file);
+            info!("Compacted grid snapshot and truncated write-ahead log");
+        }
+        Err(e) => error!("Failed to truncate write-ahead log: {}", e),
+    }
+}
+
+/// Append the live write-ahead log's current contents onto [`ARCHIVE_FILE`],
+/// then trim it back to [`MAX_ARCHIVE_EVENTS`]. Called with `wal.file`'s lock
+/// already held and already flushed, so the log can't grow between this read
+/// and the truncate that follows it, and nothing buffered in memory is
+/// missing from what's on disk.
+fn archive_log() -> std::io::Result<()> {
+    let data = match std::fs::read(WAL_FILE) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if data.is_empty() {
+        return Ok(());
+    }
+    let mut archive = OpenOptions::new().create(true).append(true).open(ARCHIVE_FILE)?;
+    archive.write_all(&data)?;
+    drop(archive);
+    trim_archive()
+}
+
+/// Drop the oldest lines of [`ARCHIVE_FILE`] once it holds more than
+/// [`MAX_ARCHIVE_EVENTS`], so neither its on-disk size nor the cost of
+/// reading it in full on every `GET /replay` grows without bound.
+fn trim_archive() -> std::io::Result<()> {
+    let data = match std::fs::read_to_string(ARCHIVE_FILE) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let lines: Vec<&str> = data.lines().filter(|line| !line.trim().is_empty()).collect();
+    if lines.len() <= MAX_ARCHIVE_EVENTS {
+        return Ok(());
+    }
+
+    let kept = &lines[lines.len() - MAX_ARCHIVE_EVENTS..];
+    let mut trimmed = kept.join("\n");
+    trimmed.push('\n');
+    std::fs::write(ARCHIVE_FILE, trimmed)
+}
+
+/// Force a final fsync and grid snapshot immediately, used once on graceful
+/// shutdown instead of waiting for the next periodic `compaction_loop` tick
+/// so a SIGTERM can't drop paints that landed since the last one.
+pub(crate) async fn final_flush(state: &SharedState, wal: &WalWriter) {
+    wal.flush_and_sync().await;
+    compact_once(state, wal).await;
+    info!("Final write-ahead log flush and grid snapshot complete");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(index: usize, color: &str, last_updated: u64, modifier_id: &str) -> LogEvent {
+        LogEvent {
+            index,
+            color: color.to_string(),
+            last_updated,
+            modifier_id: modifier_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn replay_events_applies_events_in_order_through_merge_pixel() {
+        let mut grid = vec![Pixel::default(); 2];
+        let events = vec![
+            event(0, "#111111", 1, "alice"),
+            event(0, "#222222", 2, "bob"),
+            event(1, "#333333", 1, "alice"),
+        ];
+
+        let applied = replay_events(&mut grid, &events);
+
+        assert_eq!(applied, 3);
+        assert_eq!(grid[0].color, "#222222");
+        assert_eq!(grid[1].color, "#333333");
+    }
+
+    #[test]
+    fn replay_events_skips_out_of_range_indices() {
+        let mut grid = vec![Pixel::default(); 1];
+        let events = vec![event(5, "#111111", 1, "alice")];
+
+        let applied = replay_events(&mut grid, &events);
+
+        assert_eq!(applied, 0);
+        assert_eq!(grid[0], Pixel::default());
+    }
+
+    #[test]
+    fn replay_events_ignores_a_stale_event_behind_a_newer_write() {
+        let mut grid = vec![Pixel::default(); 1];
+        let events = vec![
+            event(0, "#222222", 10, "alice"),
+            event(0, "#111111", 5, "bob"),
+        ];
+
+        let applied = replay_events(&mut grid, &events);
+
+        assert_eq!(applied, 2);
+        assert_eq!(grid[0].color, "#222222");
+        assert_eq!(grid[0].last_updated, 10);
+    }
+}
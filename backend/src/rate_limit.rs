@@ -0,0 +1,235 @@
+//! Server-enforced, adaptive per-user rate limiting for paint and chat
+//! traffic.
+//!
+//! Each user draws from a token bucket that refills at a shared rate; a
+//! `Paint`, `SendMessage` or `SendImage` consumes one token and is dropped
+//! (with a `ServerMessage::RateLimited` reply) when the bucket is empty. The
+//! refill rate itself is adaptive: `adapter_loop` watches the broadcast
+//! channel's backlog and, like a tranquilizer dose adjusted by how agitated
+//! the patient still is, nudges the rate down under load and back up as
+//! pressure eases, so throughput self-throttles toward a target instead of
+//! overflowing `BROADCAST_CAPACITY`.
+
+use crate::ServerMessage;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{broadcast, Mutex};
+use tracing::info;
+
+/// Tokens a fresh bucket starts with, and its ceiling.
+const BUCKET_CAPACITY: f64 = 10.0;
+
+/// Refill rate before any adaptive throttling kicks in, tokens/sec.
+const BASE_REFILL_PER_SEC: f64 = 3.0;
+
+/// The refill rate never drops below this fraction of the baseline, so a
+/// congested server degrades gracefully instead of stalling everyone.
+const MIN_REFILL_FACTOR: f64 = 0.1;
+
+/// Target fraction of the broadcast channel's capacity the adapter tries to
+/// hold the backlog at.
+const TARGET_LOAD: f64 = 0.5;
+
+/// How often the adapter samples channel pressure and nudges the refill rate.
+const ADAPT_INTERVAL_SECS: u64 = 1;
+
+/// How often idle buckets are swept out of the map.
+const SWEEP_INTERVAL_SECS: u64 = 120;
+
+/// A bucket untouched for this long is considered idle and dropped.
+const IDLE_TIMEOUT_SECS: u64 = 300;
+
+/// One user's token bucket.
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+    last_used: Instant,
+}
+
+impl TokenBucket {
+    fn new() -> Self {
+        let now = Instant::now();
+        TokenBucket {
+            tokens: BUCKET_CAPACITY,
+            last_refill: now,
+            last_used: now,
+        }
+    }
+
+    /// Refill at `refill_per_sec` for the elapsed time, then try to take one
+    /// token. On rejection, returns how many milliseconds until a token
+    /// would next be available.
+    fn try_consume(&mut self, refill_per_sec: f64) -> Result<(), u64> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * refill_per_sec).min(BUCKET_CAPACITY);
+        self.last_refill = now;
+        self.last_used = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if refill_per_sec > 0.0 {
+            let wait_secs = (1.0 - self.tokens) / refill_per_sec;
+            Err((wait_secs * 1000.0).ceil() as u64)
+        } else {
+            Err(1000)
+        }
+    }
+}
+
+/// Shared per-user rate limiter, plus the current adaptive refill rate.
+pub(crate) struct RateLimiter {
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+    // Stored as f64 bits so the hot `try_consume` path can read it without a
+    // lock; only `adapter_loop` ever writes it.
+    refill_per_sec_bits: AtomicU64,
+}
+
+impl RateLimiter {
+    pub(crate) fn new() -> Self {
+        RateLimiter {
+            buckets: Mutex::new(HashMap::new()),
+            refill_per_sec_bits: AtomicU64::new(BASE_REFILL_PER_SEC.to_bits()),
+        }
+    }
+
+    fn refill_rate(&self) -> f64 {
+        f64::from_bits(self.refill_per_sec_bits.load(Ordering::Relaxed))
+    }
+
+    /// Try to consume one token for `user_id`. `Err(retry_after_ms)` if the
+    /// bucket is currently empty.
+    pub(crate) async fn try_consume(&self, user_id: &str) -> Result<(), u64> {
+        let refill_rate = self.refill_rate();
+        let mut buckets = self.buckets.lock().await;
+        let bucket = buckets
+            .entry(user_id.to_string())
+            .or_insert_with(TokenBucket::new);
+        bucket.try_consume(refill_rate)
+    }
+
+    async fn sweep(&self) {
+        let mut buckets = self.buckets.lock().await;
+        let before = buckets.len();
+        buckets.retain(|_, bucket| bucket.last_used.elapsed().as_secs() < IDLE_TIMEOUT_SECS);
+        let removed = before - buckets.len();
+        if removed > 0 {
+            info!("Swept {} idle rate-limit bucket(s)", removed);
+        }
+    }
+}
+
+/// The adaptive refill-rate controller. Exits once `shutdown` fires, so it
+/// can be awaited to completion by `shutdown::WorkerRegistry::drain`.
+pub(crate) async fn adapter_loop(
+    limiter: Arc<RateLimiter>,
+    tx: broadcast::Sender<ServerMessage>,
+    channel_capacity: usize,
+    mut shutdown: tokio::sync::watch::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(tokio::time::Duration::from_secs(ADAPT_INTERVAL_SECS)) => {
+                let load = tx.len() as f64 / channel_capacity as f64;
+                let current = limiter.refill_rate();
+
+                // Above target load, aim for a lower rate (proportional to
+                // how far over target we are); at or below target, ease back
+                // toward the baseline. Moving only half the remaining gap
+                // each tick keeps the adjustment smooth instead of
+                // oscillating.
+                let target = if load > TARGET_LOAD {
+                    (BASE_REFILL_PER_SEC * (TARGET_LOAD / load))
+                        .max(BASE_REFILL_PER_SEC * MIN_REFILL_FACTOR)
+                } else {
+                    BASE_REFILL_PER_SEC
+                };
+                let next = current + (target - current) * 0.5;
+
+                limiter
+                    .refill_per_sec_bits
+                    .store(next.to_bits(), Ordering::Relaxed);
+            }
+            _ = shutdown.changed() => {
+                info!("Rate-limit adapter task shutting down");
+                break;
+            }
+        }
+    }
+}
+
+/// The idle-bucket sweeper, keeping the map from growing unbounded. Exits
+/// once `shutdown` fires.
+pub(crate) async fn sweeper_loop(limiter: Arc<RateLimiter>, mut shutdown: tokio::sync::watch::Receiver<bool>) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(tokio::time::Duration::from_secs(SWEEP_INTERVAL_SECS)) => {
+                limiter.sweep().await;
+            }
+            _ = shutdown.changed() => {
+                info!("Rate-limit sweeper task shutting down");
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_consume_succeeds_while_tokens_remain() {
+        let mut bucket = TokenBucket::new();
+        assert!(bucket.try_consume(BASE_REFILL_PER_SEC).is_ok());
+        assert_eq!(bucket.tokens, BUCKET_CAPACITY - 1.0);
+    }
+
+    #[test]
+    fn try_consume_empties_the_bucket_then_rejects() {
+        let mut bucket = TokenBucket::new();
+        for _ in 0..BUCKET_CAPACITY as usize {
+            assert!(bucket.try_consume(0.0).is_ok());
+        }
+        assert!(bucket.try_consume(0.0).is_err());
+    }
+
+    #[test]
+    fn try_consume_rejects_with_no_refill_rate() {
+        let mut bucket = TokenBucket::new();
+        bucket.tokens = 0.0;
+        assert_eq!(bucket.try_consume(0.0), Err(1000));
+    }
+
+    #[test]
+    fn try_consume_reports_wait_time_proportional_to_refill_rate() {
+        let mut bucket = TokenBucket::new();
+        bucket.tokens = 0.0;
+        bucket.last_refill = Instant::now();
+        let wait_ms = bucket.try_consume(2.0).expect_err("bucket is empty");
+        assert!((490..=510).contains(&wait_ms), "wait_ms was {wait_ms}");
+    }
+
+    #[test]
+    fn try_consume_refills_based_on_elapsed_time() {
+        let mut bucket = TokenBucket::new();
+        bucket.tokens = 0.0;
+        bucket.last_refill = Instant::now() - std::time::Duration::from_secs(1);
+
+        // At 5 tokens/sec, the elapsed second refills 5 tokens, one of which
+        // this call then consumes.
+        assert!(bucket.try_consume(5.0).is_ok());
+        assert!((bucket.tokens - 4.0).abs() < 0.1, "tokens was {}", bucket.tokens);
+    }
+
+    #[test]
+    fn try_consume_never_refills_past_capacity() {
+        let mut bucket = TokenBucket::new();
+        bucket.last_refill = Instant::now() - std::time::Duration::from_secs(1000);
+        assert!(bucket.try_consume(100.0).is_ok());
+        assert_eq!(bucket.tokens, BUCKET_CAPACITY - 1.0);
+    }
+}
@@ -0,0 +1,163 @@
+//! Coordinates graceful shutdown across axum's accept loop and the
+//! process's background workers.
+//!
+//! A single `tokio::sync::watch<bool>` is the shutdown signal: [`shutdown_trigger`]
+//! flips it to `true` on SIGINT/SIGTERM (Ctrl+C on platforms without SIGTERM)
+//! and notifies connected clients with `ServerMessage::ServerShuttingDown`.
+//! Every long-running loop - the WAL fsync task, snapshot compaction, the
+//! rate-limiter's adapter and sweeper, the chunk coalescer, and each peer
+//! dial loop in the `peering` module - selects on this signal alongside its
+//! own timer so it exits promptly instead of being dropped mid-iteration
+//! when the process ends. `ws_handler` and `peer_ws_handler` both check the
+//! same signal before upgrading a new connection; `peering::handle_peer_socket`
+//! (the inbound side of a peer session) also [`subscribe`](Shutdown::subscribe)s
+//! to it directly so an already-established session exits the same way
+//! `peering::dial_peer` does on the outbound side. [`WorkerRegistry`] tracks
+//! the `JoinHandle` of each background loop so shutdown can wait, with a
+//! timeout, for them to actually finish before main forces a final flush and
+//! exits.
+
+use crate::ServerMessage;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// How long to wait for background workers to drain after the shutdown
+/// signal is raised before giving up and flushing anyway.
+const DRAIN_TIMEOUT_SECS: u64 = 10;
+
+/// How long `axum::serve`'s graceful shutdown is given to wait for
+/// already-upgraded connections to close on their own before `main` gives up
+/// on them and proceeds to drain workers and flush anyway. Without this, a
+/// single client that never closes its socket (a backgrounded tab, or any
+/// client that doesn't react to `ServerMessage::ServerShuttingDown`) would
+/// block `axum::serve(...).await` forever, and the final flush after it
+/// would never run.
+pub(crate) const CONNECTION_DRAIN_TIMEOUT_SECS: u64 = 10;
+
+/// Shutdown signal shared by every long-running task and by `ws_handler`.
+#[derive(Clone)]
+pub(crate) struct Shutdown {
+    tx: watch::Sender<bool>,
+}
+
+impl Shutdown {
+    /// Create a fresh, unsignaled shutdown flag, plus the receiver every
+    /// background loop selects on.
+    pub(crate) fn new() -> (Self, watch::Receiver<bool>) {
+        let (tx, rx) = watch::channel(false);
+        (Shutdown { tx }, rx)
+    }
+
+    /// Whether shutdown has already been signaled - checked by `ws_handler`
+    /// before accepting a new connection.
+    pub(crate) fn is_shutting_down(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// A fresh receiver for a connection handler that needs to select on the
+    /// signal itself, e.g. `peering::handle_peer_socket` exiting an
+    /// already-established inbound peer session the same way `dial_peer`
+    /// exits an outbound one.
+    pub(crate) fn subscribe(&self) -> watch::Receiver<bool> {
+        self.tx.subscribe()
+    }
+
+    fn signal(&self) {
+        let _ = self.tx.send(true);
+    }
+}
+
+/// The future handed to `axum::serve(...).with_graceful_shutdown`: waits for
+/// SIGINT/SIGTERM, flips `shutdown` (so `ws_handler` stops taking new
+/// connections and every background loop notices on its next select), tells
+/// already-connected clients the server is going away, then returns so axum
+/// can start draining in-flight connections.
+pub(crate) async fn shutdown_trigger(shutdown: Shutdown, tx: broadcast::Sender<ServerMessage>) {
+    wait_for_signal().await;
+    info!("Shutdown signal received - closing new connections and notifying clients");
+    shutdown.signal();
+    let _ = tx.send(ServerMessage::ServerShuttingDown);
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigterm = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    let mut sigint = signal(SignalKind::interrupt()).expect("failed to install SIGINT handler");
+    tokio::select! {
+        _ = sigterm.recv() => {}
+        _ = sigint.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Owns the `JoinHandle` of every spawned long-running background loop, so
+/// shutdown can wait for them to actually finish instead of just letting
+/// `main` return out from under them.
+#[derive(Default)]
+pub(crate) struct WorkerRegistry {
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl WorkerRegistry {
+    pub(crate) fn new() -> Self {
+        WorkerRegistry::default()
+    }
+
+    /// Spawn `task` as a tracked background worker.
+    pub(crate) fn spawn(&mut self, task: impl std::future::Future<Output = ()> + Send + 'static) {
+        self.handles.push(tokio::spawn(task));
+    }
+
+    /// Wait for every tracked worker to finish, up to `DRAIN_TIMEOUT_SECS`.
+    pub(crate) async fn drain(self) {
+        let all_done = futures::future::join_all(self.handles);
+        if tokio::time::timeout(Duration::from_secs(DRAIN_TIMEOUT_SECS), all_done)
+            .await
+            .is_err()
+        {
+            warn!(
+                "Background workers didn't drain within {}s, flushing anyway",
+                DRAIN_TIMEOUT_SECS
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fresh_shutdown_is_not_signaled() {
+        let (shutdown, _rx) = Shutdown::new();
+        assert!(!shutdown.is_shutting_down());
+    }
+
+    #[test]
+    fn signal_flips_is_shutting_down_for_every_clone() {
+        let (shutdown, _rx) = Shutdown::new();
+        let cloned = shutdown.clone();
+
+        shutdown.signal();
+
+        assert!(shutdown.is_shutting_down());
+        assert!(cloned.is_shutting_down());
+    }
+
+    #[test]
+    fn signal_wakes_up_a_receiver_watching_for_it() {
+        let (shutdown, rx) = Shutdown::new();
+        assert!(rx.has_changed().is_ok_and(|changed| !changed));
+
+        shutdown.signal();
+
+        assert!(rx.has_changed().is_ok_and(|changed| changed));
+    }
+}